@@ -0,0 +1,91 @@
+/*
+ * Fuzz the parsing of guest supplied descriptor chains and frames.
+ *
+ * The input describes a short descriptor chain followed by the bytes
+ * to fill its buffers with:
+ *
+ *   byte 0      bit 0: program KEY first, bits 1-2: max write blocks - 1
+ *   byte 1      number of descriptors - 1 (mod MAX_DESCS)
+ *   4 bytes     per descriptor: len (u16 le), flags, next
+ *   ...         buffer contents, in descriptor order
+ *
+ * The device must never panic whatever the guest hands it, every
+ * chain ends in either a used length or an Error.
+ *
+ * The Vring process_queue takes can't be built outside of
+ * vhost-user-backend so we drive process_request directly, which is
+ * where all the parsing happens.
+ */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::Queue;
+
+use vhost_user_rpmb::rpmb::RpmbBackend;
+use vhost_user_rpmb::vhu_rpmb::VhostUserRpmb;
+
+const KEY: [u8; 32] = [0x42; 32];
+
+const QUEUE_SIZE: u16 = 16;
+const MAX_DESCS: usize = 8;
+const MEM_SIZE: usize = 0x10_0000;
+const DESC_TABLE: u64 = 0x1000;
+const AVAIL_RING: u64 = 0x2000;
+const USED_RING: u64 = 0x3000;
+const BUFFERS: u64 = 0x1_0000;
+const BUFFER_SIZE: u64 = 0x1000;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+    let (header, rest) = data.split_at(2);
+    let ndescs = header[1] as usize % MAX_DESCS + 1;
+    if rest.len() < ndescs * 4 {
+        return;
+    }
+    let (descs, mut contents) = rest.split_at(ndescs * 4);
+
+    let mem = GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap();
+    for (i, desc) in descs.chunks(4).enumerate() {
+        let addr = BUFFERS + i as u64 * BUFFER_SIZE;
+        let len = u16::from_le_bytes([desc[0], desc[1]]);
+        let entry = GuestAddress(DESC_TABLE + i as u64 * 16);
+        mem.write_obj(addr, entry).unwrap();
+        mem.write_obj(len as u32, GuestAddress(entry.0 + 8)).unwrap();
+        mem.write_obj(desc[2] as u16, GuestAddress(entry.0 + 12)).unwrap();
+        mem.write_obj(desc[3] as u16 % QUEUE_SIZE, GuestAddress(entry.0 + 14)).unwrap();
+
+        /* Buffers beyond their slot are left zeroed rather than overlapping */
+        let fill = contents.len().min(len as usize).min(BUFFER_SIZE as usize);
+        mem.write_slice(&contents[..fill], GuestAddress(addr)).unwrap();
+        contents = &contents[fill..];
+    }
+    mem.write_obj(0u16, GuestAddress(AVAIL_RING + 4)).unwrap();
+    mem.write_obj(1u16, GuestAddress(AVAIL_RING + 2)).unwrap();
+
+    let mem = GuestMemoryAtomic::new(mem);
+    let mut queue = Queue::new(mem, QUEUE_SIZE);
+    queue.size = QUEUE_SIZE;
+    queue.ready = true;
+    queue.desc_table = GuestAddress(DESC_TABLE);
+    queue.avail_ring = GuestAddress(AVAIL_RING);
+    queue.used_ring = GuestAddress(USED_RING);
+
+    let backend = RpmbBackend::in_memory(1);
+    if header[0] & 1 != 0 {
+        backend.program_key(KEY.iter().copied().collect()).unwrap();
+    }
+    let mut rpmb = VhostUserRpmb::new(backend).unwrap();
+    rpmb.set_max_write_blocks((header[0] >> 1 & 3) + 1).unwrap();
+
+    let chains: Vec<_> = match queue.iter() {
+        Ok(iter) => iter.collect(),
+        Err(_) => return,
+    };
+    for chain in chains {
+        let _ = rpmb.process_request(&chain);
+    }
+});
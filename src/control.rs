@@ -0,0 +1,96 @@
+/*
+ * rpmb control socket
+ *
+ * A unix socket a supervisor can use to check the daemon is actually
+ * responding rather than just still running. Each connection sends a
+ * single command line and gets a single JSON line back. The only
+ * command so far is "ping" which reports the basic device state.
+ */
+
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/* A client that connects and then says nothing isn't allowed to block us */
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    pub key_programmed: bool,
+    pub write_counter: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Reply {
+    Health(Health),
+    Error { error: String },
+}
+
+fn respond<F: Fn() -> Health>(stream: UnixStream, health: &F) -> Result<()> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let mut command = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+
+    let reply = match command.trim() {
+        "ping" => Reply::Health(health()),
+        other => Reply::Error { error: format!("unknown command {:?}", other) },
+    };
+    let mut line = serde_json::to_string(&reply)?;
+    line.push('\n');
+    (&stream).write_all(line.as_bytes())
+}
+
+/*
+ * Listen on path, answering pings with whatever health() reports.
+ * Like the vhost-user socket any stale socket left at path is
+ * replaced, but nothing else is.
+ */
+pub fn serve<F>(path: &Path, health: F) -> Result<()>
+where
+    F: Fn() -> Health + Send + 'static,
+{
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(Error::new(ErrorKind::AlreadyExists,
+                                  format!("{} exists and isn't a socket", path.display())));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    info!("control socket on {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            if let Err(e) = stream.and_then(|s| respond(s, &health)) {
+                warn!("control request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/*
+ * Ping the daemon listening on path, waiting at most timeout for it
+ * to answer.
+ */
+pub fn ping(path: &Path, timeout: Duration) -> Result<Health> {
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    (&stream).write_all(b"ping\n")?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    match serde_json::from_str::<Reply>(&line)? {
+        Reply::Health(health) => Ok(health),
+        Reply::Error { error } => Err(Error::new(ErrorKind::Other, error)),
+    }
+}
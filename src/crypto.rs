@@ -0,0 +1,123 @@
+/*
+ * rpmb crypto helpers
+ *
+ * The MAC calculations shared by the request and response paths.
+ */
+
+use crate::rpmb::RPMB_KEY_MAC_SIZE;
+use crate::vhu_rpmb::VirtIORPMBFrame;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::fmt;
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{compiler_fence, Ordering};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/*
+ * The MAC used to authenticate frames. eMMC only ever uses
+ * HMAC-SHA256 but NVMe RPMB can negotiate others, so everything goes
+ * through here rather than calling a particular MAC directly.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MacAlgorithm {
+    HmacSha256,
+}
+
+impl Default for MacAlgorithm {
+    fn default() -> Self {
+        MacAlgorithm::HmacSha256
+    }
+}
+
+impl FromStr for MacAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256-hmac" => Ok(MacAlgorithm::HmacSha256),
+            _ => Err(format!("unknown MAC algorithm {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for MacAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MacAlgorithm::HmacSha256 => write!(f, "sha256-hmac"),
+        }
+    }
+}
+
+impl MacAlgorithm {
+    pub(crate) fn compute(&self, key: &[u8], frames: &[VirtIORPMBFrame]) -> [u8; RPMB_KEY_MAC_SIZE] {
+        match self {
+            MacAlgorithm::HmacSha256 => compute_mac(key, frames),
+        }
+    }
+
+    /*
+     * The MAC we expect is a valid one for whatever the guest sent,
+     * so it never outlives the comparison.
+     */
+    pub(crate) fn verify(&self, key: &[u8], frames: &[VirtIORPMBFrame], mac: &[u8]) -> bool {
+        let expected = Zeroizing::new(self.compute(key, frames));
+        mac_eq(&expected[..], mac)
+    }
+}
+
+/*
+ * Compute the HMAC-SHA256 over a sequence of frames. For each frame
+ * the MAC covers everything from the data field up to and including
+ * req_resp, the stuff bytes and key_mac itself are excluded:
+ *
+ *   offset  size  field
+ *     0     196   stuff          (not covered)
+ *   196      32   key_mac        (not covered)
+ *   228     256   data           \
+ *   484      16   nonce           |
+ *   500       4   write_counter   |  284 bytes covered,
+ *   504       2   address         |  matching the Linux
+ *   506       2   block_count     |  kernel's rpmb_frame
+ *   508       2   result          |
+ *   510       2   req_resp       /
+ */
+fn compute_mac(key: &[u8], frames: &[VirtIORPMBFrame]) -> [u8; RPMB_KEY_MAC_SIZE] {
+    let mut mac = HmacSha256::new_from_slice(key)
+        .expect("HMAC can take key of any size");
+
+    for frame in frames {
+        mac.update(frame.mac_region());
+    }
+
+    let result = mac.finalize_reset().into_bytes().into();
+    scrub(&mut mac);
+    result
+}
+
+/*
+ * Neither hmac nor sha2 can zeroize themselves at the versions we
+ * use, and even after a reset the HMAC state holds the pads derived
+ * from the key. That state is nothing but fixed size arrays and
+ * counters, for which all zeroes is a valid value, so overwrite it
+ * in place before it is dropped. Volatile writes and the fence stop
+ * the compiler dropping the stores as dead.
+ */
+fn scrub(mac: &mut HmacSha256) {
+    let bytes = mac as *mut HmacSha256 as *mut u8;
+    for i in 0..size_of::<HmacSha256>() {
+        unsafe { bytes.add(i).write_volatile(0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/*
+ * Compare two MACs (or keys) in constant time so a guest can't use
+ * timing to work out how much of a forged MAC was correct.
+ */
+pub fn mac_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
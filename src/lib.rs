@@ -2,5 +2,13 @@
  * vhost-user-rpmb daemon, module declarations
  */
 
+pub mod control;
+pub mod crypto;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod profile;
+pub mod record;
 pub mod rpmb;
+pub mod store;
+pub mod sync;
 pub mod vhu_rpmb;
@@ -0,0 +1,90 @@
+/*
+ * Logging to a file
+ *
+ * Once daemonized there is nobody watching stderr so logs can go to
+ * a file instead. With a size limit the file is rotated to <path>.1
+ * when it fills, keeping at most one old file around.
+ */
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+use vhost_user_rpmb::sync::MutexRecover;
+
+#[derive(Debug)]
+struct LogFileState {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_size: Option<u64>,
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    /* Logs mention addresses and counters, keep them to ourselves */
+    OpenOptions::new().create(true).append(true).mode(0o600).open(path)
+}
+
+impl LogFileState {
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut old = self.path.as_os_str().to_owned();
+        old.push(".1");
+        fs::rename(&self.path, old)?;
+        self.file = open_log(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LogFile {
+    state: Arc<Mutex<LogFileState>>,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, max_size: Option<u64>) -> io::Result<LogFile> {
+        let file = open_log(path)?;
+        let written = file.metadata()?.len();
+        Ok(LogFile {
+            state: Arc::new(Mutex::new(LogFileState {
+                path: path.to_path_buf(),
+                file,
+                written,
+                max_size,
+            })),
+        })
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock_or_recover();
+        if let Some(max_size) = state.max_size {
+            if state.written > 0 && state.written + buf.len() as u64 > max_size {
+                /* Carry on in the full file rather than lose the message */
+                if let Err(e) = state.rotate() {
+                    eprintln!("vhost-user-rpmb: failed to rotate log: {}", e);
+                }
+            }
+        }
+        let len = state.file.write(buf)?;
+        state.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state.lock_or_recover().file.flush()
+    }
+}
+
+impl MakeWriter for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&self) -> Self::Writer {
+        self.clone()
+    }
+}
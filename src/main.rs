@@ -7,68 +7,881 @@
 
 #[macro_use]
 extern crate clap;
+
+mod logfile;
+
 use clap::App;
+use serde::Serialize;
 
-use log::*;
+use tracing::{error, info, warn};
 
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
 use std::process::exit;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
 
 use vhost_user_backend::{VhostUserDaemon};
 use vhost::vhost_user::{Listener};
-use vhost_user_rpmb::rpmb::RpmbBackend;
-use vhost_user_rpmb::vhu_rpmb::VhostUserRpmb;
+use vhost_user_rpmb::control;
+use vhost_user_rpmb::crypto::MacAlgorithm;
+use vhost_user_rpmb::record::{self, Recorder};
+use vhost_user_rpmb::rpmb::{load_key_file, KeyStatus, RpmbBackend, MAX_RPMB_SIZE};
+use vhost_user_rpmb::sync::LockRecover;
+use vhost_user_rpmb::vhu_rpmb::{self, VhostUserRpmb};
+
+use logfile::LogFile;
+
+/*
+ * Exit codes, kept stable so supervisors and scripts can tell what
+ * went wrong without parsing messages. A signal shows up as 128 and
+ * up so none of these can be confused with one.
+ */
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_IMAGE: i32 = 3;
+const EXIT_SOCKET: i32 = 4;
+
+/*
+ * Everything that can stop the daemon from starting. These are
+ * reported once from main() which maps them onto an exit code.
+ */
+#[derive(Debug)]
+enum MainError {
+    /// clap couldn't parse the command line, or showed help or version
+    Usage(clap::Error),
+    /// The command line doesn't make sense
+    InvalidArgs(String),
+    /// The flash image (or its sidecar files) couldn't be opened
+    Image(PathBuf, io::Error),
+    /// We couldn't get a vhost-user socket to listen on
+    Socket(String),
+    /// The device rejected its configuration
+    Device(vhu_rpmb::Error),
+    /// Failed to install the signal handlers
+    Signals(io::Error),
+    /// The vhost-user daemon failed
+    Daemon(String),
+    /// Replaying a --record log gave different results
+    Replay(usize),
+}
+
+impl fmt::Display for MainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MainError::Usage(e) => write!(f, "{}", e.message),
+            MainError::InvalidArgs(msg) => write!(f, "{}", msg),
+            MainError::Image(path, e) => write!(f, "can't open flash image {}: {}", path.display(), e),
+            MainError::Socket(msg) => write!(f, "vhost-user socket: {}", msg),
+            MainError::Device(e) => write!(f, "device setup failed: {}", e),
+            MainError::Signals(e) => write!(f, "can't install signal handlers: {}", e),
+            MainError::Daemon(msg) => write!(f, "vhost-user daemon failed: {}", msg),
+            MainError::Replay(n) => write!(f, "{} replayed chains gave different results", n),
+        }
+    }
+}
+
+impl From<clap::Error> for MainError {
+    fn from(e: clap::Error) -> Self {
+        MainError::Usage(e)
+    }
+}
+
+impl MainError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            /* --help and --version aren't failures */
+            MainError::Usage(e) if !e.use_stderr() => 0,
+            MainError::Usage(_) | MainError::InvalidArgs(_) => EXIT_USAGE,
+            MainError::Image(..) => EXIT_IMAGE,
+            MainError::Socket(_) => EXIT_SOCKET,
+            MainError::Device(_)
+            | MainError::Signals(_)
+            | MainError::Daemon(_)
+            | MainError::Replay(_) => EXIT_FAILURE,
+        }
+    }
+}
+
+/*
+ * Take over a socket that has already been created and bound for
+ * us, e.g. by systemd socket activation or the VMM itself. We insist
+ * on a listening unix socket so misconfiguration fails early.
+ */
+fn listener_from_fd(fd: RawFd) -> io::Result<Listener> {
+    let mut accepting: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN,
+                         &mut accepting as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if accepting == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                  "fd is not a listening socket"));
+    }
+
+    /* local_addr() fails for anything but a unix socket */
+    let unix = unsafe { UnixListener::from_raw_fd(fd) };
+    let addr = unix.local_addr();
+    unix.into_raw_fd();
+    addr?;
+
+    Ok(unsafe { Listener::from_raw_fd(fd) })
+}
+
+/*
+ * Parse a size in bytes with an optional K/KB or M/MB suffix.
+ */
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_uppercase();
+    let (digits, multiplier) = if let Some(n) = size.strip_suffix("KB").or_else(|| size.strip_suffix('K')) {
+        (n, 1024)
+    } else if let Some(n) = size.strip_suffix("MB").or_else(|| size.strip_suffix('M')) {
+        (n, 1024 * 1024)
+    } else {
+        (size.as_str(), 1)
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+/*
+ * What --print-capabilities reports to the frontend. The type and
+ * features follow the vhost-user backend conventions, the rest
+ * describes what this backend can do.
+ */
+#[derive(Serialize)]
+struct Capabilities {
+    #[serde(rename = "type")]
+    device_type: &'static str,
+    features: Vec<&'static str>,
+    max_size: u64,
+    supports: Vec<&'static str>,
+}
+
+fn print_capabilities() {
+    let mut features = vec!["read-only", "fd", "reconnect"];
+    if cfg!(feature = "metrics") {
+        features.push("metrics");
+    }
+    let caps = Capabilities {
+        device_type: "block",
+        features,
+        max_size: MAX_RPMB_SIZE,
+        supports: vec!["program_key", "get_write_counter", "data_write", "data_read",
+                       "result_read"],
+    };
+    println!("{}", serde_json::to_string_pretty(&caps).expect("capabilities serialize"));
+}
+
+/*
+ * The device state reported by --check.
+ */
+#[derive(Serialize)]
+struct ImageStatus {
+    image: PathBuf,
+    capacity: u8,
+    write_counter: u32,
+    key: &'static str,
+}
+
+#[derive(Clone, Copy)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+/*
+ * Open the image and any sidecar files read-only, validating them
+ * exactly as the daemon would, and report what the guest would see.
+ */
+fn check_image(flash_path: &Path, window: Option<(u64, u64)>, capacity: Option<u8>,
+               counter_path: Option<&str>, key_path: Option<&str>, format: OutputFormat)
+               -> Result<(), MainError> {
+    let mut builder = RpmbBackend::builder(flash_path).read_only(true);
+    if let Some((offset, size)) = window {
+        builder = builder.window(offset, size);
+    }
+    if let Some(capacity) = capacity {
+        builder = builder.capacity(capacity);
+    }
+    if let Some(counter_path) = counter_path {
+        builder = builder.counter_file(Path::new(counter_path));
+    }
+    if let Some(key_path) = key_path {
+        builder = builder.key_file(Path::new(key_path));
+    }
+
+    let rpmb = builder.build()
+        .map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+
+    let status = ImageStatus {
+        image: flash_path.to_path_buf(),
+        capacity: rpmb.get_capacity(),
+        write_counter: rpmb.get_write_count(),
+        key: match rpmb.key_status() {
+            KeyStatus::Programmed => "programmed",
+            KeyStatus::Empty => "not programmed",
+        },
+    };
 
-fn main() -> Result<(), String> {
+    match format {
+        OutputFormat::Json => {
+            println!("{}", serde_json::to_string_pretty(&status).expect("status serialize"));
+        }
+        OutputFormat::Text => {
+            println!("image: {}", status.image.display());
+            println!("capacity: {} x 128KB", status.capacity);
+            println!("write counter: {}", status.write_counter);
+            println!("key: {}", status.key);
+        }
+    }
+    Ok(())
+}
+
+fn log_level(verbosity: usize) -> tracing::Level {
+    match verbosity {
+        0 => tracing::Level::ERROR,
+        1 => tracing::Level::WARN,
+        2 => tracing::Level::INFO,
+        3 => tracing::Level::DEBUG,
+        _ => tracing::Level::TRACE,
+    }
+}
+
+/*
+ * stderrlog is kept for compatibility with existing log scraping,
+ * without it we log through tracing-subscriber which also shows the
+ * spans each message was logged in. A --log-file always goes through
+ * tracing-subscriber as stderrlog can only write to stderr.
+ */
+fn init_logging(verbosity: usize, log_file: Option<LogFile>) {
+    if let Some(log_file) = log_file {
+        tracing_subscriber::fmt()
+            .with_max_level(log_level(verbosity))
+            .with_ansi(false)
+            .with_writer(log_file)
+            .init();
+        return;
+    }
+
+    #[cfg(feature = "stderrlog")]
+    {
+        stderrlog::new().module(module_path!())
+            .verbosity(verbosity)
+            .timestamp(stderrlog::Timestamp::Second)
+            .init()
+            .unwrap();
+    }
+
+    #[cfg(not(feature = "stderrlog"))]
+    {
+        tracing_subscriber::fmt()
+            .with_max_level(log_level(verbosity))
+            .with_writer(io::stderr)
+            .init();
+    }
+}
+
+/*
+ * The ping command, a liveness probe for supervisors. Any answer at
+ * all means the daemon is alive, failing to get one exits with the
+ * socket error code.
+ */
+fn ping(control_socket: Option<&str>) -> Result<(), MainError> {
+    let path = control_socket.ok_or_else(|| {
+        MainError::InvalidArgs(String::from("Please specify the --control-socket to ping"))
+    })?;
+    let health = control::ping(Path::new(path), Duration::from_secs(5))
+        .map_err(|e| MainError::Socket(format!("no answer from {}: {}", path, e)))?;
+    println!("key: {}", if health.key_programmed { "programmed" } else { "empty" });
+    println!("write counter: {}", health.write_counter);
+    Ok(())
+}
+
+/*
+ * Commands for developers, only built with the debug feature and
+ * hidden from --help even then.
+ */
+#[cfg(feature = "debug")]
+fn debug_subcommands<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    use clap::{AppSettings, Arg, SubCommand};
+
+    app.setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(SubCommand::with_name("dump-block")
+                    .setting(AppSettings::Hidden)
+                    .about("Hex dump a block of the --flash-path image, bypassing all key checks")
+                    .arg(Arg::with_name("address")
+                         .required(true)
+                         .help("Block address to dump")))
+        .subcommand(SubCommand::with_name("erase")
+                    .setting(AppSettings::Hidden)
+                    .about("Zero all the data blocks of the --flash-path image, the key file is untouched"))
+        .subcommand(SubCommand::with_name("compute-mac")
+                    .setting(AppSettings::Hidden)
+                    .about("Print the MAC the device expects for a single frame")
+                    .arg(Arg::with_name("key")
+                         .required(true)
+                         .help("File holding the 32 byte key"))
+                    .arg(Arg::with_name("data")
+                         .required(true)
+                         .help("File holding the 256 bytes of block data"))
+                    .arg(Arg::with_name("address").long("address").takes_value(true)
+                         .default_value("0"))
+                    .arg(Arg::with_name("counter").long("counter").takes_value(true)
+                         .default_value("0"))
+                    .arg(Arg::with_name("block_count").long("block-count").takes_value(true)
+                         .default_value("1"))
+                    .arg(Arg::with_name("req_resp").long("req-resp").takes_value(true)
+                         .default_value("3")
+                         .help("Request or response code of the frame, DATA_WRITE by default"))
+                    .arg(Arg::with_name("nonce").long("nonce").takes_value(true)
+                         .help("Nonce as 32 hex digits, zero if not given")))
+}
+
+#[cfg(feature = "debug")]
+fn compute_mac(args: &clap::ArgMatches, algorithm: MacAlgorithm) -> Result<(), MainError> {
+    use std::convert::TryInto;
+    use vhost_user_rpmb::vhu_rpmb::MacInput;
+
+    let read_exact = |name: &str, len: usize| -> Result<Vec<u8>, MainError> {
+        let path = args.value_of(name).unwrap();
+        let bytes = fs::read(path)
+            .map_err(|e| MainError::InvalidArgs(format!("can't read {}: {}", path, e)))?;
+        if bytes.len() != len {
+            return Err(MainError::InvalidArgs(format!("{} is {} bytes, expected {}",
+                                                      path, bytes.len(), len)));
+        }
+        Ok(bytes)
+    };
+    let key = read_exact("key", 32)?;
+    let data = read_exact("data", 256)?;
+
+    let nonce = match args.value_of("nonce") {
+        Some(hex) if hex.len() == 32 => {
+            let bytes: Option<Vec<u8>> = (0..32).step_by(2)
+                .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+                .collect();
+            bytes.ok_or_else(|| MainError::InvalidArgs(String::from("--nonce must be hex")))?
+        }
+        Some(_) => return Err(MainError::InvalidArgs(String::from("--nonce must be 32 hex digits"))),
+        None => vec![0; 16],
+    };
+
+    let input = MacInput {
+        data: data.as_slice().try_into().unwrap(),
+        nonce: nonce.as_slice().try_into().unwrap(),
+        write_counter: value_t!(args, "counter", u32)?,
+        address: value_t!(args, "address", u16)?,
+        block_count: value_t!(args, "block_count", u16)?,
+        result: 0,
+        req_resp: value_t!(args, "req_resp", u16)?,
+    };
+    let mac: Vec<String> = input.mac(algorithm, &key).iter().map(|b| format!("{:02x}", b)).collect();
+    println!("{}", mac.concat());
+    Ok(())
+}
+
+#[cfg(feature = "debug")]
+fn erase(flash_path: &Path) -> Result<(), MainError> {
+    let rpmb = RpmbBackend::new(flash_path)
+        .map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+    rpmb.erase()
+        .map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+    println!("erased {} x 128KB", rpmb.get_capacity());
+    Ok(())
+}
+
+#[cfg(feature = "debug")]
+fn dump_block(flash_path: &Path, address: u16) -> Result<(), MainError> {
+    let rpmb = RpmbBackend::builder(flash_path).read_only(true).build()
+        .map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+    let block = rpmb.dump_block(address)
+        .map_err(|e| MainError::InvalidArgs(format!("can't read block {}: {}", address, e)))?;
+
+    for (i, line) in block.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        println!("{:04x}: {}", i * 16, bytes.join(" "));
+    }
+    Ok(())
+}
+
+/*
+ * Detach from the terminal with the usual double fork so we can't
+ * reacquire a controlling tty. This must happen before any threads
+ * are started as only the forking thread survives in the child.
+ *
+ * We stay in the current directory as the socket, counter and key
+ * paths may all be relative. stdin/stdout go to /dev/null as does
+ * stderr if it is a terminal, if the caller redirected it to a file
+ * we keep logging there.
+ */
+fn daemonize(pid_file: Option<&Path>) -> io::Result<()> {
+    for setsid in &[true, false] {
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            _ => unsafe { libc::_exit(0) },
+        }
+        if *setsid && unsafe { libc::setsid() } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    let devnull = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    let mut fds = vec![libc::STDIN_FILENO, libc::STDOUT_FILENO];
+    if unsafe { libc::isatty(libc::STDERR_FILENO) } == 1 {
+        fds.push(libc::STDERR_FILENO);
+    }
+    for fd in fds {
+        if unsafe { libc::dup2(devnull.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    if let Some(path) = pid_file {
+        fs::write(path, format!("{}\n", std::process::id()))?;
+    }
+    Ok(())
+}
+
+/*
+ * The image is memory mapped, so if something truncates it under us
+ * the next access to the lost pages raises SIGBUS. The store checks
+ * the size before each access but can still lose the race, in which
+ * case at least say why we died. Only async-signal-safe calls here:
+ * print the reason, restore the default action and return to fault
+ * again for the core dump.
+ */
+extern "C" fn sigbus_handler(_sig: libc::c_int) {
+    const MSG: &[u8] = b"vhost-user-rpmb: SIGBUS, has the image file been truncated?\n";
+    unsafe {
+        libc::write(libc::STDERR_FILENO, MSG.as_ptr() as *const libc::c_void, MSG.len());
+        libc::signal(libc::SIGBUS, libc::SIG_DFL);
+    }
+}
+
+fn install_sigbus_handler() {
+    let handler = sigbus_handler as extern "C" fn(libc::c_int);
+    if unsafe { libc::signal(libc::SIGBUS, handler as libc::sighandler_t) } == libc::SIG_ERR {
+        warn!("can't install SIGBUS handler: {}", io::Error::last_os_error());
+    }
+}
+
+/*
+ * With --sync-interval writes complete without waiting for the disk
+ * and we flush them from here instead. A flush with nothing written
+ * since the last one costs nothing.
+ */
+fn start_sync_thread(interval: Duration, backend: Arc<RwLock<VhostUserRpmb>>) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        if let Err(e) = backend.read_or_recover().flush() {
+            error!("background flush failed: {}", e);
+        }
+    });
+}
+
+#[cfg(feature = "metrics")]
+fn start_metrics(addr: &str, backend: Arc<RwLock<VhostUserRpmb>>) -> Result<(), MainError> {
+    use vhost_user_rpmb::metrics::{render, render_latency, serve};
+
+    let metrics = move || {
+        let vu_rpmb = backend.read_or_recover();
+        let mut body = render(&vu_rpmb.stats());
+        if let Some(latency) = vu_rpmb.latency() {
+            body.push_str(&render_latency(&latency));
+        }
+        body
+    };
+    serve(addr, metrics)
+        .map_err(|e| MainError::InvalidArgs(format!("Can't serve metrics on {}: {}", addr, e)))
+}
+
+#[cfg(not(feature = "metrics"))]
+fn start_metrics(_addr: &str, _backend: Arc<RwLock<VhostUserRpmb>>) -> Result<(), MainError> {
+    Err(MainError::InvalidArgs(String::from("--metrics-addr needs the metrics feature")))
+}
+
+fn run() -> Result<(), MainError> {
     let yaml = load_yaml!("cli.yaml");
-    let cmd_args = App::from_yaml(yaml).get_matches();
+    let app = App::from_yaml(yaml);
+    #[cfg(feature = "debug")]
+    let app = debug_subcommands(app);
+    let cmd_args = app.get_matches_safe()?;
 
     if cmd_args.is_present("print_cap") {
-        println!("{{");
-        println!("  \"type\": \"block\"");
-        println!("}}");
-        exit(0);
+        print_capabilities();
+        return Ok(());
+    }
+
+    if let ("ping", Some(_)) = cmd_args.subcommand() {
+        return ping(cmd_args.value_of("control_socket"));
     }
 
-    stderrlog::new().module(module_path!())
-        .verbosity(cmd_args.occurrences_of("verbose") as usize)
-        .timestamp(stderrlog::Timestamp::Second)
-        .init()
-        .unwrap();
+    let log_file = match cmd_args.value_of("log_file") {
+        Some(path) => {
+            let max_size = match cmd_args.value_of("log_max_size") {
+                Some(size) => Some(parse_size(size).ok_or_else(|| {
+                    MainError::InvalidArgs(String::from("Please specify a valid --log-max-size"))
+                })?),
+                None => None,
+            };
+            Some(LogFile::open(Path::new(path), max_size).map_err(|e| {
+                MainError::InvalidArgs(format!("Can't open log file {}: {}", path, e))
+            })?)
+        }
+        None => None,
+    };
+    init_logging(cmd_args.occurrences_of("verbose") as usize, log_file);
+    install_sigbus_handler();
 
-    let flash_path = Path::new(cmd_args.value_of("flash_path").unwrap());
-    if !flash_path.exists() {
-            println!("Please specify a valid --flash-path for the \
-                      flash image");
-            exit(1);
+    #[cfg(feature = "debug")]
+    {
+        if let ("dump-block", Some(sub_args)) = cmd_args.subcommand() {
+            let flash_path = cmd_args.value_of("flash_path").ok_or_else(|| {
+                MainError::InvalidArgs(String::from("Please specify a --flash-path to dump"))
+            })?;
+            let address = value_t!(sub_args, "address", u16)?;
+            return dump_block(Path::new(flash_path), address);
+        }
+        if let ("compute-mac", Some(sub_args)) = cmd_args.subcommand() {
+            let algorithm = value_t!(cmd_args, "mac_algo", MacAlgorithm)?;
+            return compute_mac(sub_args, algorithm);
+        }
+        if let ("erase", Some(_)) = cmd_args.subcommand() {
+            let flash_path = cmd_args.value_of("flash_path").ok_or_else(|| {
+                MainError::InvalidArgs(String::from("Please specify a --flash-path to erase"))
+            })?;
+            return erase(Path::new(flash_path));
+        }
     }
 
-    let rpmb = match RpmbBackend::new(&flash_path) {
-        Ok(s) => s,
-        Err(e) => {
-            println!("Can't open flash image {}: {}", flash_path.display(), e);
-            exit(-1);
+    let window = match cmd_args.value_of("image_offset") {
+        Some(offset) => {
+            let offset = parse_size(offset).ok_or_else(|| {
+                MainError::InvalidArgs(String::from("Please specify a valid --image-offset"))
+            })?;
+            let size = cmd_args.value_of("size").and_then(parse_size).ok_or_else(|| {
+                MainError::InvalidArgs(String::from("Please specify a valid --size for the window"))
+            })?;
+            Some((offset, size))
         }
+        None => None
     };
 
-    let socket = match cmd_args.value_of("socket") {
-        Some(path) => path,
-        None => {
-            error!("Failed to retrieve vhost-user socket path");
-            exit(-1);
+    let capacity = if cmd_args.is_present("capacity") {
+        Some(value_t!(cmd_args, "capacity", u8)?)
+    } else {
+        None
+    };
+
+    if cmd_args.is_present("check") {
+        let format = match cmd_args.value_of("format") {
+            Some("json") => OutputFormat::Json,
+            _ => OutputFormat::Text,
+        };
+        return check_image(Path::new(cmd_args.value_of("flash_path").unwrap()), window, capacity,
+                           cmd_args.value_of("counter_file"),
+                           cmd_args.value_of("key_file"), format);
+    }
+
+    let create_size = if cmd_args.is_present("create") {
+        let size = cmd_args.value_of("size").and_then(parse_size).ok_or_else(|| {
+            MainError::InvalidArgs(String::from("Please specify a valid --size for the flash image"))
+        })?;
+        Some(size)
+    } else {
+        None
+    };
+
+    let flash_path = Path::new(cmd_args.value_of("flash_path").ok_or_else(|| {
+        MainError::InvalidArgs(String::from("Please specify a --flash-path for the flash image"))
+    })?);
+    if !flash_path.exists() && create_size.is_none() {
+        return Err(MainError::InvalidArgs(String::from(
+            "Please specify a valid --flash-path for the flash image")));
+    }
+
+    let mac_algorithm = value_t!(cmd_args, "mac_algo", MacAlgorithm)?;
+    let mut builder = RpmbBackend::builder(flash_path)
+        .read_only(cmd_args.is_present("read_only"))
+        .direct_io(cmd_args.is_present("direct_io"))
+        .reserve_tail(cmd_args.is_present("reserve_tail"))
+        .mac_algorithm(mac_algorithm);
+    if let Some((offset, size)) = window {
+        builder = builder.window(offset, size);
+    }
+    if let Some(capacity) = capacity {
+        builder = builder.capacity(capacity);
+    }
+    if cmd_args.is_present("cache_blocks") {
+        builder = builder.cache_blocks(value_t!(cmd_args, "cache_blocks", usize)?);
+    }
+    if cmd_args.is_present("sync_on_write") {
+        builder = builder.sync_on_write(true);
+    }
+    let sync_interval = if cmd_args.is_present("sync_interval") {
+        let ms = value_t!(cmd_args, "sync_interval", u64)?;
+        if ms == 0 {
+            return Err(MainError::InvalidArgs(String::from("--sync-interval must be non-zero")));
         }
+        builder = builder.sync_on_write(false);
+        Some(Duration::from_millis(ms))
+    } else {
+        None
     };
+    if let Some(size) = create_size {
+        builder = builder
+            .create_size(size)
+            .preallocate(cmd_args.is_present("preallocate"));
+    }
+    if let Some(counter_path) = cmd_args.value_of("counter_file") {
+        builder = builder.counter_file(Path::new(counter_path));
+    }
+    if cmd_args.is_present("counter") {
+        builder = builder.initial_counter(value_t!(cmd_args, "counter", u32)?);
+    }
+    if cmd_args.is_present("key_set") {
+        warn!("ignoring --key-set, the device is keyed if the --key-file exists");
+    }
+    /* A replay starts unkeyed, the key is for the recorded PROGRAM_KEY */
+    let key_file = cmd_args.value_of("key_file").filter(|_| !cmd_args.is_present("replay"));
+    if let Some(key_path) = key_file {
+        builder = builder.key_file(Path::new(key_path));
+    }
+    if let Some(mirror_path) = cmd_args.value_of("mirror") {
+        builder = builder
+            .mirror(Path::new(mirror_path))
+            .mirror_strict(cmd_args.is_present("mirror_strict"))
+            .mirror_verify(cmd_args.is_present("mirror_verify"));
+    }
+
+    let rpmb = builder.build()
+        .map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+
+    let mut vu_rpmb = VhostUserRpmb::new(rpmb).map_err(MainError::Device)?;
 
-    let listener = Listener::new(socket, true).unwrap();
+    if cmd_args.is_present("queue_size") {
+        let queue_size = value_t!(cmd_args, "queue_size", usize)?;
+        vu_rpmb.set_queue_size(queue_size).map_err(|e| {
+            MainError::InvalidArgs(format!("Invalid --queue-size {}: {}", queue_size, e))
+        })?;
+    }
+    if let Some(serial) = cmd_args.value_of("serial") {
+        vu_rpmb.set_serial(serial);
+    }
+    if cmd_args.is_present("max_write_blocks") {
+        let max_write_blocks = value_t!(cmd_args, "max_write_blocks", u8)?;
+        vu_rpmb.set_max_write_blocks(max_write_blocks).map_err(|e| {
+            MainError::InvalidArgs(format!("Invalid --max-write-blocks {}: {}", max_write_blocks, e))
+        })?;
+    }
+    if cmd_args.is_present("max_auth_failures") {
+        let max_auth_failures = value_t!(cmd_args, "max_auth_failures", u32)?;
+        vu_rpmb.set_max_auth_failures(max_auth_failures);
+    }
+    if cmd_args.is_present("profile") {
+        vu_rpmb.enable_profiling();
+    }
+    if cmd_args.is_present("response_delay_ms") {
+        let ms = value_t!(cmd_args, "response_delay_ms", u64)?;
+        vu_rpmb.set_response_delay(Duration::from_millis(ms));
+    }
+    if cmd_args.is_present("strict_nonce") {
+        vu_rpmb.set_strict_nonce(true);
+    }
+    if cmd_args.is_present("reject_zero_key") {
+        vu_rpmb.set_reject_zero_key(true);
+    }
+    if cmd_args.is_present("no_indirect_desc") {
+        vu_rpmb.disable_indirect_desc();
+    }
+    if cmd_args.is_present("no_event_idx") {
+        vu_rpmb.disable_event_idx();
+    }
 
-    let backend = Arc::new(RwLock::new(VhostUserRpmb::new(rpmb).unwrap()));
+    if let Some(path) = cmd_args.value_of("replay") {
+        let key = match cmd_args.value_of("key_file") {
+            Some(key_path) => Some(load_key_file(Path::new(key_path))
+                .map_err(|e| MainError::InvalidArgs(format!("Can't read {}: {}", key_path, e)))?),
+            None => None,
+        };
+        let report = record::replay(Path::new(path), &vu_rpmb, key.as_deref())
+            .map_err(|e| MainError::InvalidArgs(format!("Can't replay {}: {}", path, e)))?;
+        vu_rpmb.sync().map_err(|e| MainError::Image(flash_path.to_path_buf(), e))?;
+        println!("replayed {} chains, {} with different results", report.chains, report.mismatches);
+        /* Handy for sizing --cache-blocks against a real workload */
+        let stats = vu_rpmb.stats();
+        if stats.cache_hits + stats.cache_misses > 0 {
+            println!("block cache {} hits, {} misses", stats.cache_hits, stats.cache_misses);
+        }
+        if report.mismatches > 0 {
+            return Err(MainError::Replay(report.mismatches));
+        }
+        return Ok(());
+    }
+    if let Some(path) = cmd_args.value_of("record") {
+        let recorder = Recorder::open(Path::new(path))
+            .map_err(|e| MainError::InvalidArgs(format!("Can't open {}: {}", path, e)))?;
+        vu_rpmb.set_recorder(recorder);
+    }
 
-    let mut daemon =
-        VhostUserDaemon::new(String::from("vhost-user-rpmb-backend"), backend.clone()).unwrap();
+    let (listener, socket_path) = if cmd_args.is_present("fd") {
+        let fd = value_t!(cmd_args, "fd", RawFd)?;
+        let listener = listener_from_fd(fd)
+            .map_err(|e| MainError::Socket(format!("can't use fd {}: {}", fd, e)))?;
+        (listener, None)
+    } else {
+        let path = cmd_args.value_of("socket").ok_or_else(|| {
+            MainError::InvalidArgs(String::from("Please specify a --socket-path or --fd"))
+        })?;
+        let listener = Listener::new(path, true)
+            .map_err(|e| MainError::Socket(format!("can't listen on {}: {:?}", path, e)))?;
+        (listener, Some(String::from(path)))
+    };
 
-    daemon.start(listener).unwrap();
-    daemon.wait().unwrap();
 
-    Ok(())
+    /*
+     * Only background once the image and socket are set up so any
+     * startup errors are still seen by whoever launched us. A socket
+     * activated daemon is already managed by its supervisor.
+     */
+    if cmd_args.is_present("daemonize") {
+        if cmd_args.is_present("fd") {
+            warn!("not daemonizing, the socket was passed in with --fd");
+        } else {
+            let pid_file = cmd_args.value_of("pid_file").map(Path::new);
+            daemonize(pid_file)
+                .map_err(|e| MainError::Daemon(format!("can't daemonize: {}", e)))?;
+        }
+    }
+
+    let backend = Arc::new(RwLock::new(vu_rpmb));
+
+    if let Some(addr) = cmd_args.value_of("metrics_addr") {
+        start_metrics(addr, backend.clone())?;
+    }
+
+    if let Some(path) = cmd_args.value_of("control_socket") {
+        let health_backend = backend.clone();
+        let health = move || {
+            let vu_rpmb = health_backend.read_or_recover();
+            let rpmb = vu_rpmb.backend();
+            control::Health {
+                key_programmed: rpmb.has_key(),
+                write_counter: rpmb.get_write_count(),
+            }
+        };
+        control::serve(Path::new(path), health)
+            .map_err(|e| MainError::Socket(format!("can't serve control socket {}: {}", path, e)))?;
+    }
+
+    if let Some(interval) = sync_interval {
+        start_sync_thread(interval, backend.clone());
+    }
+
+    /*
+     * SIGUSR1 logs what we negotiated with the frontend and the
+     * device stats, without disturbing anything.
+     */
+    let mut dump_signals = Signals::new(&[SIGUSR1]).map_err(MainError::Signals)?;
+    let dump_backend = backend.clone();
+    thread::spawn(move || {
+        for _ in dump_signals.forever() {
+            info!("device state: {}", dump_backend.read_or_recover().state_report());
+        }
+    });
+
+    /*
+     * On SIGINT/SIGTERM flush everything to disk and clean up the
+     * socket before exiting.
+     */
+    let mut signals = Signals::new(&[SIGINT, SIGTERM]).map_err(MainError::Signals)?;
+    let shutdown_backend = backend.clone();
+    /* We only clean up sockets we created ourselves */
+    let cleanup_paths: Vec<String> = if cmd_args.is_present("no_cleanup") {
+        Vec::new()
+    } else {
+        socket_path.iter().cloned()
+            .chain(cmd_args.value_of("control_socket").map(String::from))
+            .collect()
+    };
+    thread::spawn(move || {
+        if let Some(sig) = signals.forever().next() {
+            info!("shutting down on signal {}", sig);
+            let vu_rpmb = shutdown_backend.read_or_recover();
+            /* Stop the event loop before the final flush */
+            if let Err(e) = vu_rpmb.exit_event.write(1) {
+                warn!("failed to signal exit event: {}", e);
+            }
+            if let Err(e) = vu_rpmb.sync() {
+                error!("failed to sync device state: {}", e);
+            }
+            for path in &cleanup_paths {
+                if let Err(e) = fs::remove_file(path) {
+                    warn!("failed to remove {}: {}", path, e);
+                }
+            }
+            exit(0);
+        }
+    });
+
+    /*
+     * In reconnect mode we go back to listening whenever the
+     * frontend goes away, with the same backend so the key and
+     * counter survive the VMM restarting.
+     */
+    let reconnect = cmd_args.is_present("reconnect");
+    let mut listener = listener;
+    loop {
+        let mut daemon =
+            VhostUserDaemon::new(String::from("vhost-user-rpmb-backend"), backend.clone())
+            .map_err(|e| MainError::Daemon(format!("{:?}", e)))?;
+
+        daemon.start(listener).map_err(|e| MainError::Daemon(format!("{:?}", e)))?;
+        let result = daemon.wait();
+
+        if !reconnect {
+            result.map_err(|e| MainError::Daemon(format!("{:?}", e)))?;
+            if let Err(e) = backend.read_or_recover().sync() {
+                error!("failed to sync device state: {}", e);
+            }
+            return Ok(());
+        }
+
+        match result {
+            Ok(_) => info!("frontend disconnected, waiting for a new connection"),
+            Err(e) => info!("frontend disconnected ({:?}), waiting for a new connection", e),
+        }
+        backend.write_or_recover().disconnect();
+
+        /* --reconnect can't be combined with --fd so we always have a path */
+        let path = socket_path.as_deref().expect("reconnect needs a socket path");
+        listener = Listener::new(path, true)
+            .map_err(|e| MainError::Socket(format!("can't listen on {}: {:?}", path, e)))?;
+    }
+}
+
+fn main() {
+    if let Err(e) = run() {
+        match &e {
+            /* clap has already formatted these */
+            MainError::Usage(usage) if !usage.use_stderr() => println!("{}", usage.message),
+            MainError::Usage(usage) => eprintln!("{}", usage.message),
+            _ => eprintln!("vhost-user-rpmb: {}", e),
+        }
+        exit(e.exit_code());
+    }
 }
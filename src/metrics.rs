@@ -0,0 +1,113 @@
+/*
+ * rpmb metrics export
+ *
+ * A deliberately tiny HTTP server that answers every request with
+ * the device stats in the Prometheus text format. It only needs to
+ * satisfy a scraper, so no routing, keep-alive or chunking.
+ */
+
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+use tracing::{info, warn};
+
+use crate::profile::LatencyStats;
+use crate::rpmb::RpmbStats;
+
+/*
+ * Render the stats as Prometheus metrics.
+ */
+pub fn render(stats: &RpmbStats) -> String {
+    let metrics = [
+        ("program_key_total", "counter", "PROGRAM_KEY requests handled",
+         stats.program_key_requests),
+        ("data_write_total", "counter", "DATA_WRITE requests handled",
+         stats.write_requests),
+        ("data_read_total", "counter", "DATA_READ requests served",
+         stats.reads),
+        ("auth_failures_total", "counter", "Requests rejected for a bad MAC",
+         stats.auth_failures),
+        ("addr_failures_total", "counter", "Requests rejected for a bad address",
+         stats.addr_failures),
+        ("cache_hits_total", "counter", "Blocks read from the block cache",
+         stats.cache_hits),
+        ("cache_misses_total", "counter", "Blocks the block cache had to read from the image",
+         stats.cache_misses),
+        ("current_write_counter", "gauge", "Current value of the write counter",
+         stats.writes),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics.iter() {
+        let _ = writeln!(out, "# HELP rpmb_{} {}", name, help);
+        let _ = writeln!(out, "# TYPE rpmb_{} {}", name, kind);
+        let _ = writeln!(out, "rpmb_{} {}", name, value);
+    }
+    out
+}
+
+/*
+ * Render --profile request timings, as summaries in seconds.
+ */
+pub fn render_latency(latency: &[(&str, LatencyStats)]) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "# HELP rpmb_request_seconds Time taken to handle each type of request");
+    let _ = writeln!(out, "# TYPE rpmb_request_seconds summary");
+    for (name, stats) in latency {
+        let _ = writeln!(out, "rpmb_request_seconds_sum{{request=\"{}\"}} {}",
+                         name, stats.total.as_secs_f64());
+        let _ = writeln!(out, "rpmb_request_seconds_count{{request=\"{}\"}} {}",
+                         name, stats.count);
+    }
+    let _ = writeln!(out, "# TYPE rpmb_request_seconds_min gauge");
+    for (name, stats) in latency {
+        let _ = writeln!(out, "rpmb_request_seconds_min{{request=\"{}\"}} {}",
+                         name, stats.min.as_secs_f64());
+    }
+    let _ = writeln!(out, "# TYPE rpmb_request_seconds_max gauge");
+    for (name, stats) in latency {
+        let _ = writeln!(out, "rpmb_request_seconds_max{{request=\"{}\"}} {}",
+                         name, stats.max.as_secs_f64());
+    }
+    out
+}
+
+fn respond(stream: TcpStream, body: &str) -> Result<()> {
+    /* Swallow the request, we answer the same whatever was asked */
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = stream;
+    write!(stream,
+           "HTTP/1.0 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\n\r\n{}",
+           body.len(), body)
+}
+
+/*
+ * Serve metrics on addr from a background thread. metrics is called
+ * afresh for every scrape to render the body.
+ */
+pub fn serve<A, F>(addr: A, metrics: F) -> Result<()>
+where
+    A: ToSocketAddrs,
+    F: Fn() -> String + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    info!("serving metrics on {}", listener.local_addr()?);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|s| respond(s, &metrics()));
+            if let Err(e) = result {
+                warn!("metrics request failed: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
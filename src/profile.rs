@@ -0,0 +1,83 @@
+/*
+ * rpmb request profiling
+ *
+ * With --profile every request is timed and the spread for each type
+ * is logged periodically at debug level. That is usually enough to
+ * tell whether flushing, MAC calculation or the guest itself is the
+ * reason things feel slow.
+ */
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/* How often the accumulated timings are logged */
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug)]
+pub struct LatencyStats {
+    pub count: u64,
+    pub total: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStats {
+    fn new(elapsed: Duration) -> Self {
+        LatencyStats { count: 1, total: elapsed, min: elapsed, max: elapsed }
+    }
+
+    fn record(&mut self, elapsed: Duration) {
+        self.count += 1;
+        self.total += elapsed;
+        self.min = self.min.min(elapsed);
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn average(&self) -> Duration {
+        self.total / self.count as u32
+    }
+}
+
+/*
+ * Timings for each type of request since the daemon started.
+ */
+#[derive(Debug)]
+pub struct RequestProfile {
+    requests: BTreeMap<&'static str, LatencyStats>,
+    last_report: Instant,
+}
+
+impl Default for RequestProfile {
+    fn default() -> Self {
+        RequestProfile { requests: BTreeMap::new(), last_report: Instant::now() }
+    }
+}
+
+impl RequestProfile {
+    pub fn record(&mut self, request: &'static str, elapsed: Duration) {
+        self.requests
+            .entry(request)
+            .and_modify(|s| s.record(elapsed))
+            .or_insert_with(|| LatencyStats::new(elapsed));
+    }
+
+    pub fn snapshot(&self) -> Vec<(&'static str, LatencyStats)> {
+        self.requests.iter().map(|(name, stats)| (*name, *stats)).collect()
+    }
+
+    /*
+     * Log the timings if it has been long enough since we last did.
+     */
+    pub fn maybe_report(&mut self) {
+        if self.last_report.elapsed() < REPORT_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        for (name, stats) in &self.requests {
+            debug!("{}: {} requests, min {:?} avg {:?} max {:?}",
+                   name, stats.count, stats.min, stats.average(), stats.max);
+        }
+    }
+}
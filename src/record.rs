@@ -0,0 +1,188 @@
+/*
+ * rpmb request recording
+ *
+ * With --record every descriptor chain the device handles is appended
+ * to a log, one JSON object per line. Each line has the complete
+ * request frames, so --replay can push them through a fresh device
+ * and check it gives the same results as the original. That makes a
+ * guest reported bug reproducible without the guest.
+ *
+ * The key a PROGRAM_KEY frame carries is never written to the log,
+ * so it can be attached to a bug report. Replaying the signed
+ * requests needs the key supplied separately.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+use vm_memory::ByteValued;
+use zeroize::Zeroizing;
+
+use crate::sync::MutexRecover;
+use crate::rpmb::RPMB_KEY_MAC_SIZE;
+use crate::vhu_rpmb::{self, VhostUserRpmb, VirtIORPMBFrame, VIRTIO_RPMB_REQ_PROGRAM_KEY};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedFrame {
+    pub req_resp: u16,
+    pub address: u16,
+    pub block_count: u16,
+    pub nonce: String,
+    /* The whole frame, for the replay */
+    pub frame: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedChain {
+    pub requests: Vec<RecordedFrame>,
+    /* Sizes of the writeable buffers the guest gave us */
+    pub responses: Vec<u32>,
+    /* Result of each reply, empty if the chain was dropped */
+    pub results: Vec<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+type Replies = std::result::Result<Vec<VirtIORPMBFrame>, vhu_rpmb::Error>;
+
+fn results(replies: &Replies) -> (Vec<u16>, Option<String>) {
+    match replies {
+        Ok(frames) => (frames.iter().map(|f| f.result.to_native()).collect(), None),
+        Err(e) => (Vec::new(), Some(e.to_string())),
+    }
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    /*
+     * Append to the log at path, creating it if needed. It holds
+     * whatever the guest wrote so keep it private.
+     */
+    pub fn open(path: &Path) -> Result<Recorder> {
+        use std::os::unix::fs::OpenOptionsExt;
+
+        let file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+        Ok(Recorder { file: Mutex::new(file) })
+    }
+
+    /*
+     * Log a chain and what we made of it. Failing to record is only
+     * worth a warning, the guest shouldn't notice.
+     */
+    pub(crate) fn record(&self, frames: &[VirtIORPMBFrame], buf_lens: &[u32], replies: &Replies) {
+        let (results, error) = results(replies);
+        let chain = RecordedChain {
+            requests: frames
+                .iter()
+                .map(|f| {
+                    let mut frame = Zeroizing::new(*f);
+                    if frame.req_resp.to_native() == VIRTIO_RPMB_REQ_PROGRAM_KEY {
+                        frame.key_mac = [0; RPMB_KEY_MAC_SIZE];
+                    }
+                    RecordedFrame {
+                        req_resp: f.req_resp.to_native(),
+                        address: f.address.to_native(),
+                        block_count: f.block_count.to_native(),
+                        nonce: to_hex(&f.nonce),
+                        frame: to_hex(frame.as_slice()),
+                    }
+                })
+                .collect(),
+            responses: buf_lens.to_vec(),
+            results,
+            error,
+        };
+
+        let mut line = match serde_json::to_string(&chain) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("can't encode request record: {}", e);
+                return;
+            }
+        };
+        line.push('\n');
+        if let Err(e) = self.file.lock_or_recover().write_all(line.as_bytes()) {
+            warn!("can't write request record: {}", e);
+        }
+    }
+}
+
+/*
+ * How a replay went. A mismatch is a chain where the device didn't
+ * give the results it gave when the log was recorded.
+ */
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub chains: usize,
+    pub mismatches: usize,
+}
+
+/*
+ * Feed every chain in the log at path through rpmb in order. The
+ * device should start in the same state as the recorded one did,
+ * normally that means a fresh image. Any PROGRAM_KEY programs key,
+ * without it the redacted all zero key is programmed and the signed
+ * requests that follow won't give their recorded results.
+ */
+pub fn replay(path: &Path, rpmb: &VhostUserRpmb, key: Option<&[u8]>) -> Result<ReplayReport> {
+    if key.map_or(false, |key| key.len() != RPMB_KEY_MAC_SIZE) {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              format!("key must be {} bytes", RPMB_KEY_MAC_SIZE)));
+    }
+    let invalid = |line: usize, what: &str| {
+        Error::new(ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, what))
+    };
+    let mut report = ReplayReport::default();
+
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let chain: RecordedChain = serde_json::from_str(&line)
+            .map_err(|e| invalid(n + 1, &e.to_string()))?;
+
+        let mut frames = Zeroizing::new(Vec::new());
+        for request in &chain.requests {
+            let mut frame = from_hex(&request.frame)
+                .and_then(|bytes| VirtIORPMBFrame::from_slice(&bytes).copied())
+                .ok_or_else(|| invalid(n + 1, "bad frame"))?;
+            if let (VIRTIO_RPMB_REQ_PROGRAM_KEY, Some(key)) = (request.req_resp, key) {
+                frame.key_mac.copy_from_slice(key);
+            }
+            frames.push(frame);
+        }
+
+        let (results, error) = results(&rpmb.process_frames(&frames, &chain.responses));
+
+        report.chains += 1;
+        if results != chain.results || error.is_some() != chain.error.is_some() {
+            warn!("chain {} gave {:?} {:?}, recorded {:?} {:?}",
+                  n + 1, results, error, chain.results, chain.error);
+            report.mismatches += 1;
+        }
+    }
+
+    Ok(report)
+}
@@ -5,52 +5,121 @@
  *
  */
 
-use std::path::Path;
-use std::fs::File;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
 use std::fs::OpenOptions;
-use std::io::{Result, Error, ErrorKind};
+use std::io::{Result, Error, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::convert::TryFrom;
-use std::sync::RwLock;
-use memmap::{MmapMut, MmapOptions};
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
 use arrayvec::ArrayVec;
 use core::fmt::Debug;
+use crate::crypto::{mac_eq, MacAlgorithm};
+#[cfg(feature = "mem-store")]
+use crate::store::MemStore;
+use crate::store::{CacheStore, DirectStore, FileStore, ImageWindow, MirrorStore, RpmbStore};
+use crate::sync::{LockRecover, MutexRecover};
+use zeroize::{Zeroize, Zeroizing};
+use tracing::{error, info, warn};
 
 const KB: u64 = 1024;
-const UNIT_128KB: u64 = KB * 128;
-const MAX_RPMB_SIZE: u64 = UNIT_128KB * 128;
+pub(crate) const UNIT_128KB: u64 = KB * 128;
+pub const MAX_RPMB_SIZE: u64 = UNIT_128KB * 128;
+
+/* How many recent nonces are remembered to catch reuse */
+pub const NONCE_WINDOW: usize = 32;
 
 pub const RPMB_KEY_MAC_SIZE: usize = 32;
 pub const RPMB_BLOCK_SIZE: usize = 256;
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 enum Key {
     Empty,
     Programmed(ArrayVec::<u8, RPMB_KEY_MAC_SIZE>)
 }
 
+/* Scrub the key material rather than leaving it in freed memory */
+impl Drop for Key {
+    fn drop(&mut self) {
+        if let Key::Programmed(k) = self {
+            k.as_mut_slice().zeroize();
+        }
+    }
+}
+
+/* Keys are secret so never compare them with a short-circuiting == */
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Key::Empty, Key::Empty) => true,
+            (Key::Programmed(a), Key::Programmed(b)) => mac_eq(a, b),
+            _ => false
+        }
+    }
+}
+
+/*
+ * Whether a key has been programmed, without the key itself. This is
+ * all anything outside the crate gets to know about the key.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyStatus {
+    Empty,
+    Programmed,
+}
+
 #[derive(Debug)]
 pub enum KeyError {
     ProgramFailed,
     NoKey
 }
 
+/*
+ * A snapshot of how much the device has been used.
+ */
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RpmbStats {
+    pub writes: u32,
+    pub reads: u32,
+    pub program_key_requests: u32,
+    pub write_requests: u32,
+    pub auth_failures: u32,
+    pub addr_failures: u32,
+    pub cache_hits: u32,
+    pub cache_misses: u32,
+}
+
 /*
  * These encapsulates all the mutable state we need to track
- * associated with the RPMB device.
+ * associated with the RPMB device. The read count is atomic so
+ * reads only ever need to take the lock for reading.
  */
 #[derive(Debug)]
 struct RpmbMutableState {
-    write_count: u32,
-    read_count: u32,
+    write_count: AtomicU32,
+    read_count: AtomicU32,
+    program_key_requests: AtomicU32,
+    write_requests: AtomicU32,
+    auth_failures: u32,
+    addr_failures: u32,
+    recent_nonces: VecDeque<[u8; 16]>,
     key: Key,
 }
 
 impl RpmbMutableState {
-    pub fn new() -> Result<RpmbMutableState> {
+    pub fn new(write_count: u32, key: Key) -> Result<RpmbMutableState> {
         Ok(RpmbMutableState {
-            write_count: 0,
-            read_count: 0,
-            key: Key::Empty
+            write_count: AtomicU32::new(write_count),
+            read_count: AtomicU32::new(0),
+            program_key_requests: AtomicU32::new(0),
+            write_requests: AtomicU32::new(0),
+            auth_failures: 0,
+            addr_failures: 0,
+            recent_nonces: VecDeque::with_capacity(NONCE_WINDOW),
+            key
         })
     }
 
@@ -62,65 +131,744 @@ impl RpmbMutableState {
             Err(KeyError::ProgramFailed)
         }
     }
+
+    pub fn increment_write_count(&self) {
+        self.write_count.fetch_add(1, Ordering::AcqRel);
+    }
 }
 
 
 #[derive(Debug)]
 pub struct RpmbBackend {
-    image: File,
-    mmap: MmapMut,
-    capacity: u8,
+    store: Box<dyn RpmbStore>,
+    /* Advertised capacity when less than the store provides */
+    capacity_limit: Option<u8>,
+    write_lock: Mutex<()>,
+    mac_algorithm: MacAlgorithm,
+    counter_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
     state: RwLock<RpmbMutableState>
 }
 
+/*
+ * The write counter is persisted as a 4 byte little endian value in
+ * a sidecar file. A missing file means we have never written.
+ */
+fn load_counter(counter_path: &Path) -> Result<u32> {
+    /* A crash part way through persisting, the last good value stands */
+    if counter_temp_path(counter_path).exists() {
+        warn!("ignoring incomplete update of {}", counter_path.display());
+    }
+
+    if !counter_path.exists() {
+        return Ok(0);
+    }
+
+    let mut buf = Vec::new();
+    File::open(counter_path)?.read_to_end(&mut buf)?;
+    let bytes = <[u8; 4]>::try_from(buf.as_slice())
+        .map_err(|_e| Error::new(ErrorKind::InvalidData,
+                                 "Counter file is corrupt"))?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/*
+ * The counter is replaced by writing it to a temporary file next to
+ * the real one and renaming it over the top. Either the old or the
+ * new value survives a crash, never a torn mix of both. The
+ * directory is synced too so the rename itself is durable.
+ */
+fn counter_temp_path(counter_path: &Path) -> PathBuf {
+    let mut temp = counter_path.as_os_str().to_owned();
+    temp.push(".tmp");
+    PathBuf::from(temp)
+}
+
+fn persist_counter_file(counter_path: &Path, write_count: u32) -> Result<()> {
+    let temp_path = counter_temp_path(counter_path);
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&write_count.to_le_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, counter_path)?;
+
+    let dir = match counter_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/*
+ * The key is stored raw in its own file. As the key can only be
+ * programmed once a bad key file is fatal, we don't want to silently
+ * come up unkeyed and let the guest program a different one.
+ */
+pub fn load_key_file(key_path: &Path) -> Result<ArrayVec<u8, RPMB_KEY_MAC_SIZE>> {
+    let mut buf = Vec::new();
+    File::open(key_path)?.read_to_end(&mut buf)?;
+    let key = <[u8; RPMB_KEY_MAC_SIZE]>::try_from(buf.as_slice())
+        .map_err(|_e| Error::new(ErrorKind::InvalidData,
+                                 format!("key file is {} bytes, expected {}",
+                                         buf.len(), RPMB_KEY_MAC_SIZE)))?;
+    Ok(ArrayVec::from(key))
+}
+
+fn persist_key(key_path: &Path, key: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key_path)?;
+    file.write_all(key)?;
+    file.sync_all()
+}
+
+/*
+ * Make sure every block of the image is backed by real zeroed
+ * storage rather than holes. fallocate does it without any I/O but
+ * not every filesystem supports it, in which case we write zeros.
+ */
+fn preallocate_image(image: &mut File, size: u64) -> Result<()> {
+    let ret = unsafe { libc::fallocate(image.as_raw_fd(), 0, 0, size as libc::off_t) };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = Error::last_os_error();
+    if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+        return Err(err);
+    }
+
+    let zeros = vec![0; UNIT_128KB as usize];
+    for _ in 0..size / UNIT_128KB {
+        image.write_all(&zeros)?;
+    }
+    Ok(())
+}
+
+/*
+ * Create a new zero filled image of size bytes. The size must be a
+ * whole number of 128KB units and fit within the maximum RPMB size.
+ * Unless preallocated the file is sparse.
+ */
+fn create_image(image_path: &Path, size: u64, preallocate: bool) -> Result<()> {
+    if size == 0 || size % UNIT_128KB != 0 {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "Image size must be a multiple of 128KB"));
+    }
+    if size > MAX_RPMB_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput,
+                              "Image size is larger than maximum size supported"));
+    }
+
+    let mut image = OpenOptions::new().write(true).create_new(true).open(image_path)?;
+    if preallocate {
+        preallocate_image(&mut image, size)?;
+    }
+    image.set_len(size)?;
+    image.sync_all()
+}
+
+/*
+ * Collects the optional configuration for a RpmbBackend. Only the
+ * image path is required, everything else defaults to off.
+ */
+#[derive(Debug, Default)]
+pub struct RpmbBackendBuilder {
+    image_path: PathBuf,
+    counter_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+    initial_counter: Option<u32>,
+    read_only: bool,
+    create_size: Option<u64>,
+    preallocate: bool,
+    window: Option<ImageWindow>,
+    capacity: Option<u8>,
+    no_sync_on_write: bool,
+    direct_io: bool,
+    reserve_tail: bool,
+    cache_blocks: usize,
+    mirror_path: Option<PathBuf>,
+    mirror_strict: bool,
+    mirror_verify: bool,
+    mac_algorithm: MacAlgorithm,
+}
+
+impl RpmbBackendBuilder {
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /*
+     * The key file persists a programmed key, if it already exists
+     * the device starts already keyed.
+     */
+    pub fn key_file(mut self, key_path: &Path) -> Self {
+        self.key_path = Some(key_path.to_path_buf());
+        self
+    }
+
+    pub fn counter_file(mut self, counter_path: &Path) -> Self {
+        self.counter_path = Some(counter_path.to_path_buf());
+        self
+    }
+
+    /*
+     * Start the write counter at this value instead of zero, e.g. to
+     * bring up a device part way through its life. It can only move
+     * a counter loaded from the counter file forwards, and is saved
+     * there straight away.
+     */
+    pub fn initial_counter(mut self, write_count: u32) -> Self {
+        self.initial_counter = Some(write_count);
+        self
+    }
+
+    /*
+     * Create the image with this size if it doesn't already exist.
+     */
+    pub fn create_size(mut self, size: u64) -> Self {
+        self.create_size = Some(size);
+        self
+    }
+
+    /*
+     * Only use size bytes of the image starting at offset.
+     */
+    pub fn window(mut self, offset: u64, size: u64) -> Self {
+        self.window = Some(ImageWindow { offset, size });
+        self
+    }
+
+    pub fn mac_algorithm(mut self, mac_algorithm: MacAlgorithm) -> Self {
+        self.mac_algorithm = mac_algorithm;
+        self
+    }
+
+    /*
+     * Advertise (and allow access to) only this many 128KB units,
+     * leaving the rest of the image alone.
+     */
+    pub fn capacity(mut self, capacity: u8) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    /*
+     * Flush every write to disk before reporting it complete (the
+     * default). Without it the caller must flush() periodically and
+     * a crash can lose writes since the last flush, although the
+     * write counter is still persisted on every write.
+     */
+    pub fn sync_on_write(mut self, sync_on_write: bool) -> Self {
+        self.no_sync_on_write = !sync_on_write;
+        self
+    }
+
+    /*
+     * Only map the whole 128KB units we advertise, limited by
+     * capacity() if given. Whatever follows them in the file is left
+     * alone for the caller's own metadata, and it doesn't need to be
+     * a whole number of units.
+     */
+    pub fn reserve_tail(mut self, reserve_tail: bool) -> Self {
+        self.reserve_tail = reserve_tail;
+        self
+    }
+
+    /*
+     * Keep up to this many recently used blocks in memory, see
+     * CacheStore. Zero, the default, means no cache.
+     */
+    pub fn cache_blocks(mut self, cache_blocks: usize) -> Self {
+        self.cache_blocks = cache_blocks;
+        self
+    }
+
+    /*
+     * Access the image with O_DIRECT rather than mapping it, see
+     * DirectStore. Writes are always synchronous.
+     */
+    pub fn direct_io(mut self, direct_io: bool) -> Self {
+        self.direct_io = direct_io;
+        self
+    }
+
+    /*
+     * Also apply every write to a mirror image, see MirrorStore.
+     */
+    pub fn mirror(mut self, mirror_path: &Path) -> Self {
+        self.mirror_path = Some(mirror_path.to_path_buf());
+        self
+    }
+
+    /*
+     * Fail writes that can't be mirrored rather than just logging.
+     */
+    pub fn mirror_strict(mut self, strict: bool) -> Self {
+        self.mirror_strict = strict;
+        self
+    }
+
+    /*
+     * Compare every read against the mirror and log any difference.
+     */
+    pub fn mirror_verify(mut self, verify: bool) -> Self {
+        self.mirror_verify = verify;
+        self
+    }
+
+    /*
+     * Fully allocate a newly created image instead of leaving it
+     * sparse. Existing images are left alone.
+     */
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    pub fn build(self) -> Result<RpmbBackend> {
+        let image_path = self.image_path.as_path();
+
+        if self.read_only && self.create_size.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Can't create a read-only image"));
+        }
+        if self.window.is_some() && self.create_size.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Can't create an image to use a window of"));
+        }
+
+        if let Some(size) = self.create_size {
+            if !image_path.exists() {
+                create_image(image_path, size, self.preallocate)?;
+            }
+        }
+
+        /*
+         * A reserved tail is just a window over the start of the
+         * image, one that a bad capacity still fails to fit.
+         */
+        let window = match self.window {
+            None if self.reserve_tail => {
+                let units = (image_path.metadata()?.len().min(MAX_RPMB_SIZE) / UNIT_128KB) as u8;
+                let units = match self.capacity {
+                    Some(capacity) if capacity >= 1 && capacity <= units => capacity,
+                    _ => units,
+                };
+                Some(ImageWindow { offset: 0, size: units as u64 * UNIT_128KB })
+            }
+            window => window,
+        };
+
+        let store: Box<dyn RpmbStore> = if self.direct_io {
+            if self.no_sync_on_write {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "Direct I/O writes are always synchronous"));
+            }
+            Box::new(DirectStore::open(image_path, self.read_only, window)?)
+        } else {
+            let mut store = FileStore::open(image_path, self.read_only, window)?;
+            store.set_sync_on_write(!self.no_sync_on_write);
+            Box::new(store)
+        };
+        if let Some(capacity) = self.capacity {
+            if capacity == 0 || capacity > store.capacity() {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("Capacity must be between 1 and {} x 128KB",
+                                              store.capacity())));
+            }
+        }
+
+        let store: Box<dyn RpmbStore> = match &self.mirror_path {
+            Some(mirror_path) => Box::new(MirrorStore::open(store, mirror_path,
+                                                            self.mirror_strict,
+                                                            self.mirror_verify)?),
+            None => store,
+        };
+        let store: Box<dyn RpmbStore> = match self.cache_blocks {
+            0 => store,
+            blocks => Box::new(CacheStore::new(store, blocks)),
+        };
+
+        let mut backend = RpmbBackend::from_parts(store, self.mac_algorithm,
+                                                  self.counter_path, self.key_path)?;
+        backend.capacity_limit = self.capacity;
+        if let Some(write_count) = self.initial_counter {
+            let current = backend.get_write_count();
+            if write_count < current {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("Initial counter {} is behind the saved counter {}",
+                                              write_count, current)));
+            }
+            backend.store_write_count(write_count);
+            backend.persist_counter()?;
+        }
+        Ok(backend)
+    }
+}
+
 impl RpmbBackend {
+    pub fn builder(image_path: &Path) -> RpmbBackendBuilder {
+        RpmbBackendBuilder {
+            image_path: image_path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
     pub fn new(image_path: &Path) -> Result<RpmbBackend> {
+        RpmbBackend::builder(image_path).build()
+    }
 
-        let image = OpenOptions::new().read(true).write(true).open(image_path)?;
-        let metadata = image.metadata()?;
+    /*
+     * A device over an arbitrary store with no counter or key files,
+     * everything is lost when it is dropped.
+     */
+    pub fn with_store(store: Box<dyn RpmbStore>) -> RpmbBackend {
+        RpmbBackend::from_parts(store, MacAlgorithm::default(), None, None)
+            .expect("no files to load")
+    }
 
-        let mut len = metadata.len();
-        if len > MAX_RPMB_SIZE {
-            println!("{} is larger than maximum size supported", image_path.display());
-            len = MAX_RPMB_SIZE;
-        }
-        let mmap = unsafe { MmapOptions::new()
-                            .len(len as usize)
-                            .map_mut(&image)? };
+    /*
+     * A device held entirely in memory, handy for tests.
+     */
+    #[cfg(feature = "mem-store")]
+    pub fn in_memory(capacity: u8) -> RpmbBackend {
+        RpmbBackend::with_store(Box::new(MemStore::new(capacity)))
+    }
+
+    fn from_parts(store: Box<dyn RpmbStore>, mac_algorithm: MacAlgorithm,
+                  counter_path: Option<PathBuf>, key_path: Option<PathBuf>)
+                  -> Result<RpmbBackend> {
+        let write_count = match &counter_path {
+            Some(path) => load_counter(path)?,
+            None => 0
+        };
 
-        let capacity:u8 = u8::try_from(len / UNIT_128KB)
-            .map_err(|_e| Error::new(ErrorKind::InvalidData, "More
-            capacity than can be accessed!"))?;
+        let key = match &key_path {
+            Some(path) if path.exists() => Key::Programmed(load_key_file(path)?),
+            _ => Key::Empty
+        };
 
-        let state = RwLock::new(RpmbMutableState::new().unwrap());
+        let state = RwLock::new(RpmbMutableState::new(write_count, key).unwrap());
 
         Ok(RpmbBackend {
-            image,
-            mmap,
-            capacity,
+            store,
+            capacity_limit: None,
+            write_lock: Mutex::new(()),
+            mac_algorithm,
+            counter_path,
+            key_path,
             state
         })
     }
 
+    fn limit_capacity(&self, capacity: u8) -> u8 {
+        self.capacity_limit.map_or(capacity, |limit| limit.min(capacity))
+    }
+
     pub fn get_capacity(&self) -> u8 {
-        self.capacity
+        self.limit_capacity(self.store.capacity())
+    }
+
+    /*
+     * Size of the device as the guest sees it, in bytes.
+     */
+    pub fn capacity_bytes(&self) -> u64 {
+        self.get_capacity() as u64 * UNIT_128KB
+    }
+
+    /*
+     * Number of blocks the guest can address.
+     */
+    pub fn block_count(&self) -> u32 {
+        (self.capacity_bytes() / RPMB_BLOCK_SIZE as u64) as u32
+    }
+
+    pub fn block_size(&self) -> usize {
+        RPMB_BLOCK_SIZE
+    }
+
+    /*
+     * Pick up any change in the size of the backing store, returning
+     * the new capacity in 128KB units.
+     */
+    pub fn refresh_capacity(&self) -> Result<u8> {
+        Ok(self.limit_capacity(self.store.refresh_capacity()?))
+    }
+
+    /*
+     * Grow the backing store to size bytes.
+     */
+    #[cfg(feature = "grow")]
+    pub fn grow(&self, size: u64) -> Result<u8> {
+        Ok(self.limit_capacity(self.store.grow(size)?))
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.store.is_read_only()
+    }
+
+    pub fn mac_algorithm(&self) -> MacAlgorithm {
+        self.mac_algorithm
     }
 
     pub fn get_write_count(&self) -> u32 {
-        self.state.read().unwrap().write_count
+        self.state.read_or_recover().write_count.load(Ordering::Acquire)
+    }
+
+    pub fn increment_write_count(&self) {
+        self.state.read_or_recover().increment_write_count();
+    }
+
+    /*
+     * Serialise authenticated writes. The counter check, the data
+     * write and the counter increment must all happen under this
+     * guard so two writes can never be accepted with the same
+     * counter. Readers of the counter don't need it.
+     */
+    pub fn lock_writes(&self) -> MutexGuard<()> {
+        self.write_lock.lock_or_recover()
+    }
+
+    pub fn record_read(&self) {
+        /* fetch_add wraps on overflow */
+        self.state.read_or_recover().read_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_program_key_request(&self) {
+        self.state.read_or_recover().program_key_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_write_request(&self) {
+        self.state.read_or_recover().write_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /*
+     * Count a request with a bad MAC, returning the number seen
+     * since the last reset.
+     */
+    pub fn record_auth_failure(&self) -> u32 {
+        let mut state = self.state.write_or_recover();
+        state.auth_failures = state.auth_failures.wrapping_add(1);
+        state.auth_failures
+    }
+
+    pub fn record_addr_failure(&self) {
+        let mut state = self.state.write_or_recover();
+        state.addr_failures = state.addr_failures.wrapping_add(1);
+    }
+
+    /*
+     * Remember a nonce from a read request, returning false if it is
+     * one of the last NONCE_WINDOW we saw. The window starts afresh
+     * on reset.
+     */
+    pub fn record_nonce(&self, nonce: [u8; 16]) -> bool {
+        let mut state = self.state.write_or_recover();
+        if state.recent_nonces.contains(&nonce) {
+            return false;
+        }
+        if state.recent_nonces.len() == NONCE_WINDOW {
+            state.recent_nonces.pop_front();
+        }
+        state.recent_nonces.push_back(nonce);
+        true
+    }
+
+    pub fn stats(&self) -> RpmbStats {
+        let state = self.state.read_or_recover();
+        let cache = self.store.cache_stats();
+        RpmbStats {
+            writes: state.write_count.load(Ordering::Acquire),
+            reads: state.read_count.load(Ordering::Relaxed),
+            program_key_requests: state.program_key_requests.load(Ordering::Relaxed),
+            write_requests: state.write_requests.load(Ordering::Relaxed),
+            auth_failures: state.auth_failures,
+            addr_failures: state.addr_failures,
+            cache_hits: cache.hits,
+            cache_misses: cache.misses,
+        }
+    }
+
+    fn store_write_count(&self, write_count: u32) {
+        self.state.read_or_recover().write_count.store(write_count, Ordering::Release);
+    }
+
+    /*
+     * Force the write counter to any value, backwards included, e.g.
+     * to reach expiry quickly. Only for tests, a real device must
+     * never let the counter go back.
+     */
+    #[cfg(feature = "test-util")]
+    #[doc(hidden)]
+    pub fn set_write_count(&self, write_count: u32) {
+        self.store_write_count(write_count);
+    }
+
+    /*
+     * Save the current write counter to the counter file (if we have
+     * one) and make sure it has hit the disk before we return. The
+     * file is replaced atomically, see persist_counter_file.
+     */
+    pub fn persist_counter(&self) -> Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
+        if let Some(path) = &self.counter_path {
+            persist_counter_file(path, self.get_write_count())?;
+        }
+        Ok(())
+    }
+
+    /*
+     * Check count blocks starting at address all fit within the
+     * device. The sum is done in u32 so a guest can't wrap around
+     * the end of the u16 address space.
+     */
+    pub fn check_range(&self, address: u16, count: u16) -> std::result::Result<(), ()> {
+        if address as u32 + count as u32 > self.block_count() {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+
+    /*
+     * Write whole blocks of data starting at block address. The
+     * write must fit entirely inside the advertised capacity.
+     */
+    pub fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        if data.len() % RPMB_BLOCK_SIZE != 0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                                  "Data is not a whole number of blocks"));
+        }
+
+        let count = u16::try_from(data.len() / RPMB_BLOCK_SIZE)
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput, "Too many blocks"))?;
+        self.check_range(address, count)
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput,
+                                     "Write beyond end of device"))?;
+
+        self.store.write_blocks(address, data)
+    }
+
+    /*
+     * Flush the whole store back to disk.
+     */
+    pub fn flush(&self) -> Result<()> {
+        self.store.flush()
     }
 
+    /*
+     * Zero every data block, e.g. to reuse an image between test
+     * runs. Like real hardware there is no way to erase the key and
+     * the write counter only ever goes forward.
+     */
+    pub fn erase(&self) -> Result<()> {
+        let _writes = self.lock_writes();
+        self.store.write_blocks(0, &vec![0; self.capacity_bytes() as usize])?;
+        self.flush()
+    }
+
+    /*
+     * Make sure everything we hold is on disk, used on shutdown.
+     */
+    pub fn sync(&self) -> Result<()> {
+        info!("syncing device, stats: {:?}", self.stats());
+        self.flush()?;
+        self.persist_counter()
+    }
+
+    /*
+     * Return a copy of count blocks starting at block address.
+     */
+    pub fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        self.check_range(address, count)
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput,
+                                     "Read beyond end of device"))?;
+
+        self.store.read_blocks(address, count)
+    }
+
+    /*
+     * Raw contents of a single block for offline debugging. There is
+     * no MAC and no key check, it is never reachable by the guest.
+     */
+    pub fn dump_block(&self, address: u16) -> Result<[u8; RPMB_BLOCK_SIZE]> {
+        let data = self.read_blocks(address, 1)?;
+        let mut block = [0; RPMB_BLOCK_SIZE];
+        block.copy_from_slice(&data);
+        Ok(block)
+    }
+
+    /*
+     * Reset the device state as if the device had been power cycled.
+     * Like the programmed key the write counter is preserved, real
+     * hardware never moves it backwards and doing so would let every
+     * previously signed write be replayed. Only the stats and the
+     * nonces we have seen are cleared.
+     */
+    pub fn reset(&self) -> Result<()> {
+        info!("resetting device, stats: {:?}", self.stats());
+
+        let mut state = self.state.write_or_recover();
+        let write_count = state.write_count.load(Ordering::Acquire);
+        let key = state.key.clone();
+        *state = RpmbMutableState::new(write_count, key)?;
+        Ok(())
+    }
+
+    /*
+     * The key is persisted before we update our state so we never
+     * report success for a key that would be lost on restart.
+     */
     pub fn program_key(&self, key: ArrayVec<u8, RPMB_KEY_MAC_SIZE>) -> std::result::Result<(), KeyError> {
-        let result =  self.state.write().unwrap().program_key(key);
-        return result;
+        let mut state = self.state.write_or_recover();
+
+        if let Key::Programmed(_) = state.key {
+            return Err(KeyError::ProgramFailed);
+        }
+
+        if self.is_read_only() {
+            return Err(KeyError::ProgramFailed);
+        }
+
+        if let Some(path) = &self.key_path {
+            if let Err(e) = persist_key(path, &key) {
+                error!("Failed to persist key to {}: {}", path.display(), e);
+                return Err(KeyError::ProgramFailed);
+            }
+        }
+
+        state.program_key(key)
+    }
+
+    pub fn key_status(&self) -> KeyStatus {
+        match self.state.read_or_recover().key {
+            Key::Empty => KeyStatus::Empty,
+            Key::Programmed(_) => KeyStatus::Programmed,
+        }
+    }
+
+    pub fn has_key(&self) -> bool {
+        self.key_status() == KeyStatus::Programmed
     }
 
-    pub fn get_key(&self) -> std::result::Result
-        <ArrayVec<u8, RPMB_KEY_MAC_SIZE>, KeyError> {
-            let key = self.state.read().unwrap().key.clone();
-            match key {
+    /*
+     * Return a copy of the key which is scrubbed once the caller is
+     * done with it. Only the MAC code gets to see the key.
+     */
+    pub(crate) fn get_key(&self) -> std::result::Result
+        <Zeroizing<[u8; RPMB_KEY_MAC_SIZE]>, KeyError> {
+            match &self.state.read_or_recover().key {
                 Key::Empty => { Err(KeyError::NoKey) }
-                Key::Programmed(k) => { Ok(k)}
+                Key::Programmed(k) => {
+                    let mut key = Zeroizing::new([0; RPMB_KEY_MAC_SIZE]);
+                    key.copy_from_slice(k);
+                    Ok(key)
+                }
             }
     }
 }
@@ -0,0 +1,816 @@
+/*
+ * rpmb backing stores
+ *
+ * The RpmbBackend deals with keys and counters, the data itself
+ * lives in a store. Normally that is a memory mapped image file but
+ * tests can use a plain buffer instead.
+ */
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{Result, Error, ErrorKind};
+use std::convert::TryFrom;
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use core::fmt::Debug;
+use memmap::{Mmap, MmapMut, MmapOptions};
+use tracing::{info, warn};
+
+use crate::rpmb::{MAX_RPMB_SIZE, RPMB_BLOCK_SIZE, UNIT_128KB};
+use crate::sync::{LockRecover, MutexRecover};
+
+/*
+ * Somewhere to keep the blocks of an RPMB device. Callers have
+ * already checked the blocks are within capacity() but stores still
+ * fail rather than panic if they are not.
+ */
+pub trait RpmbStore: Debug + Send + Sync {
+    /* Size of the store in 128KB units */
+    fn capacity(&self) -> u8;
+
+    fn is_read_only(&self) -> bool;
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>>;
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()>;
+
+    fn flush(&self) -> Result<()>;
+
+    /*
+     * Pick up any change in size made behind our back, returning the
+     * new capacity.
+     */
+    fn refresh_capacity(&self) -> Result<u8> {
+        Ok(self.capacity())
+    }
+
+    /*
+     * Grow the store to size bytes.
+     */
+    #[cfg(feature = "grow")]
+    fn grow(&self, _size: u64) -> Result<u8> {
+        Err(Error::new(ErrorKind::Other, "Store can't be grown"))
+    }
+
+    /*
+     * How well any block cache in front of the store is doing.
+     */
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u32,
+    pub misses: u32,
+}
+
+/*
+ * Byte range of the blocks, if they fit in a store of len bytes.
+ */
+fn block_range(address: u16, bytes: usize, len: usize) -> Result<Range<usize>> {
+    let start = address as usize * RPMB_BLOCK_SIZE;
+    let end = start + bytes;
+    if end > len {
+        return Err(Error::new(ErrorKind::InvalidInput, "Access beyond end of store"));
+    }
+    Ok(start..end)
+}
+
+/*
+ * Work out how many 128KB units an image of len bytes provides.
+ */
+fn image_capacity(len: u64) -> Result<u8> {
+    if len == 0 || len % UNIT_128KB != 0 {
+        return Err(Error::new(ErrorKind::InvalidData,
+                              "Image is not a whole number of 128KB units"));
+    }
+    u8::try_from(len / UNIT_128KB)
+        .map_err(|_e| Error::new(ErrorKind::InvalidData, "More
+            capacity than can be accessed!"))
+}
+
+/*
+ * The image is mapped writeable unless we are running read-only in
+ * which case any attempt to modify it fails.
+ */
+#[derive(Debug)]
+enum ImageMap {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl ImageMap {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            ImageMap::ReadWrite(m) => m,
+            ImageMap::ReadOnly(m) => m,
+        }
+    }
+
+    fn as_mut_slice(&mut self) -> Result<&mut [u8]> {
+        match self {
+            ImageMap::ReadWrite(m) => Ok(m),
+            ImageMap::ReadOnly(_) => Err(Error::new(ErrorKind::PermissionDenied,
+                                                    "Image is read-only")),
+        }
+    }
+
+    fn flush_range(&self, offset: usize, len: usize) -> Result<()> {
+        match self {
+            ImageMap::ReadWrite(m) => m.flush_range(offset, len),
+            ImageMap::ReadOnly(_) => Ok(()),
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        match self {
+            ImageMap::ReadWrite(m) => m.flush(),
+            ImageMap::ReadOnly(_) => Ok(()),
+        }
+    }
+}
+
+fn map_image(image: &File, offset: u64, len: u64, read_only: bool) -> Result<ImageMap> {
+    let mut options = MmapOptions::new();
+    options.offset(offset as usize).len(len as usize);
+    if read_only {
+        Ok(ImageMap::ReadOnly(unsafe { options.map(image)? }))
+    } else {
+        Ok(ImageMap::ReadWrite(unsafe { options.map_mut(image)? }))
+    }
+}
+
+/*
+ * The part of a larger file that holds the RPMB data, e.g. when it
+ * is embedded in a composite firmware image.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct ImageWindow {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/*
+ * The offset and length of the part of image we use and how many
+ * 128KB units that gives us.
+ */
+fn image_extent(image_path: &Path, image: &File, window: Option<ImageWindow>) -> Result<(u64, u64, u8)> {
+    let file_len = image.metadata()?.len();
+
+    let (offset, len) = match window {
+        Some(ImageWindow { offset, size }) => {
+            if offset.checked_add(size).map_or(true, |end| end > file_len) {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("{} bytes at offset {} is past the end of {}",
+                                              size, offset, image_path.display())));
+            }
+            if size > MAX_RPMB_SIZE {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      "Window is larger than maximum size supported"));
+            }
+            (offset, size)
+        }
+        None => {
+            if file_len > MAX_RPMB_SIZE {
+                warn!("{} is larger than maximum size supported", image_path.display());
+            }
+            (0, file_len.min(MAX_RPMB_SIZE))
+        }
+    };
+
+    let capacity = image_capacity(len)
+        .map_err(|e| Error::new(ErrorKind::InvalidData,
+                                format!("{}: {}", image_path.display(), e)))?;
+    Ok((offset, len, capacity))
+}
+
+/*
+ * A memory mapped image file, or a window into one.
+ */
+#[derive(Debug)]
+pub struct FileStore {
+    image: File,
+    mmap: RwLock<ImageMap>,
+    capacity: AtomicU8,
+    window: Option<ImageWindow>,
+    sync_on_write: bool,
+    dirty: AtomicBool,
+}
+
+impl FileStore {
+    /*
+     * Map the whole image, or if window is given just size bytes of
+     * it starting at offset.
+     */
+    pub fn open(image_path: &Path, read_only: bool, window: Option<ImageWindow>) -> Result<FileStore> {
+        let image = OpenOptions::new().read(true).write(!read_only).open(image_path)?;
+        let (offset, len, capacity) = image_extent(image_path, &image, window)?;
+        let mmap = map_image(&image, offset, len, read_only)?;
+
+        let store = FileStore {
+            image,
+            mmap: RwLock::new(mmap),
+            capacity: AtomicU8::new(capacity),
+            window,
+            sync_on_write: true,
+            dirty: AtomicBool::new(false),
+        };
+        store.check_backing(len as usize)?;
+        Ok(store)
+    }
+
+    /*
+     * Touching a page of the map past the end of the file raises
+     * SIGBUS, which is what happens if something else truncates the
+     * image while we are running. There is no way to make that safe
+     * but checking the file still covers the map before each access
+     * turns the common case into an I/O error for the guest rather
+     * than a crash.
+     */
+    fn check_backing(&self, mapped: usize) -> Result<()> {
+        let offset = self.window.map_or(0, |w| w.offset);
+        let file_len = self.image.metadata()?.len();
+        if file_len < offset + mapped as u64 {
+            warn!("image truncated to {} bytes, {} bytes are mapped", file_len, offset + mapped as u64);
+            return Err(Error::new(ErrorKind::UnexpectedEof, "Image has been truncated"));
+        }
+        Ok(())
+    }
+
+    /*
+     * By default every write is flushed before it completes. Without
+     * sync_on_write writes only dirty the map and it is up to the
+     * caller to flush() it regularly.
+     */
+    pub fn set_sync_on_write(&mut self, sync_on_write: bool) {
+        self.sync_on_write = sync_on_write;
+    }
+}
+
+impl RpmbStore for FileStore {
+    fn capacity(&self) -> u8 {
+        self.capacity.load(Ordering::Acquire)
+    }
+
+    fn is_read_only(&self) -> bool {
+        matches!(*self.mmap.read_or_recover(), ImageMap::ReadOnly(_))
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        let mmap = self.mmap.read_or_recover();
+        let slice = mmap.as_slice();
+        let range = block_range(address, count as usize * RPMB_BLOCK_SIZE, slice.len())?;
+        self.check_backing(slice.len())?;
+        Ok(slice[range].to_vec())
+    }
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        let mut mmap = self.mmap.write_or_recover();
+        let slice = mmap.as_mut_slice()?;
+        let range = block_range(address, data.len(), slice.len())?;
+        self.check_backing(slice.len())?;
+        slice[range.clone()].copy_from_slice(data);
+
+        /* Don't report success until the data is durable */
+        if self.sync_on_write {
+            mmap.flush_range(range.start, data.len())
+        } else {
+            self.dirty.store(true, Ordering::Release);
+            Ok(())
+        }
+    }
+
+    /*
+     * Nothing to do if nothing has been written since the last flush,
+     * so periodic flushing of an idle device is free.
+     */
+    fn flush(&self) -> Result<()> {
+        if self.sync_on_write || self.dirty.swap(false, Ordering::AcqRel) {
+            let mmap = self.mmap.read_or_recover();
+            if let Err(e) = self.check_backing(mmap.as_slice().len()).and_then(|_| mmap.flush()) {
+                self.dirty.store(true, Ordering::Release);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+     * Re-read the image size from the file and re-map it if it has
+     * changed underneath us, e.g. because it was resized out of band.
+     */
+    fn refresh_capacity(&self) -> Result<u8> {
+        /* A window is a fixed size, the rest of the file isn't ours */
+        if self.window.is_some() {
+            return Ok(self.capacity());
+        }
+
+        let len = self.image.metadata()?.len().min(MAX_RPMB_SIZE);
+        let capacity = image_capacity(len)?;
+
+        /* Hold the map lock so nobody sees the old map with the new size */
+        let mut mmap = self.mmap.write_or_recover();
+        if capacity != self.capacity() {
+            let read_only = matches!(*mmap, ImageMap::ReadOnly(_));
+            mmap.flush()?;
+            *mmap = map_image(&self.image, 0, len, read_only)?;
+            self.capacity.store(capacity, Ordering::Release);
+            info!("image capacity is now {} x 128KB", capacity);
+        }
+        Ok(capacity)
+    }
+
+    /*
+     * Grow the image to size bytes, allocating the new space up front
+     * so later writes can't fail with ENOSPC.
+     */
+    #[cfg(feature = "grow")]
+    fn grow(&self, size: u64) -> Result<u8> {
+        use std::os::unix::io::AsRawFd;
+
+        if size % UNIT_128KB != 0 || size > MAX_RPMB_SIZE {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  "Image size must be a multiple of 128KB up to the maximum size"));
+        }
+        if self.is_read_only() {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Image is read-only"));
+        }
+        if self.window.is_some() {
+            return Err(Error::new(ErrorKind::InvalidInput, "Can't grow a window of an image"));
+        }
+        if size < self.capacity() as u64 * UNIT_128KB {
+            return Err(Error::new(ErrorKind::InvalidInput, "Can't shrink the image"));
+        }
+
+        let ret = unsafe { libc::fallocate(self.image.as_raw_fd(), 0, 0, size as libc::off_t) };
+        if ret < 0 {
+            return Err(Error::last_os_error());
+        }
+        self.refresh_capacity()
+    }
+}
+
+/*
+ * O_DIRECT wants the file offset, length and memory of every access
+ * aligned to the logical block size of the device underneath. 4KB
+ * covers everything we are likely to meet.
+ */
+const DIRECT_ALIGN: usize = 4096;
+
+/*
+ * A zeroed buffer aligned for O_DIRECT.
+ */
+struct AlignedBuf {
+    ptr: *mut u8,
+    layout: Layout,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> AlignedBuf {
+        let layout = Layout::from_size_align(len, DIRECT_ALIGN).expect("valid layout");
+        let ptr = unsafe { alloc_zeroed(layout) };
+        if ptr.is_null() {
+            handle_alloc_error(layout);
+        }
+        AlignedBuf { ptr, layout }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.layout.size()) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.layout.size()) }
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.ptr, self.layout) }
+    }
+}
+
+/*
+ * An image file accessed with O_DIRECT|O_DSYNC instead of through a
+ * map, so a write is on the disk when write_blocks returns without
+ * depending on when msync gets around to it. RPMB blocks are smaller
+ * than the alignment O_DIRECT needs so writes read, modify and write
+ * back whole sectors.
+ */
+#[derive(Debug)]
+pub struct DirectStore {
+    image: File,
+    offset: u64,
+    len: u64,
+    capacity: u8,
+    read_only: bool,
+    /* Two writes to the same sector mustn't undo each other */
+    write_lock: Mutex<()>,
+}
+
+impl DirectStore {
+    pub fn open(image_path: &Path, read_only: bool, window: Option<ImageWindow>) -> Result<DirectStore> {
+        let image = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(libc::O_DIRECT | libc::O_DSYNC)
+            .open(image_path)
+            .map_err(|e| match e.raw_os_error() {
+                Some(libc::EINVAL) => Error::new(ErrorKind::InvalidInput,
+                                                 format!("{}: the filesystem doesn't support O_DIRECT",
+                                                         image_path.display())),
+                _ => e,
+            })?;
+        let (offset, len, capacity) = image_extent(image_path, &image, window)?;
+        if offset % DIRECT_ALIGN as u64 != 0 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                                  format!("O_DIRECT needs the image offset aligned to {} bytes",
+                                          DIRECT_ALIGN)));
+        }
+
+        Ok(DirectStore {
+            image,
+            offset,
+            len,
+            capacity,
+            read_only,
+            write_lock: Mutex::new(()),
+        })
+    }
+
+    /*
+     * Read the aligned sectors covering range of the store, returning
+     * them with where range starts inside them.
+     */
+    fn read_sectors(&self, range: &Range<usize>) -> Result<(AlignedBuf, usize)> {
+        let start = range.start - range.start % DIRECT_ALIGN;
+        let end = (range.end + DIRECT_ALIGN - 1) / DIRECT_ALIGN * DIRECT_ALIGN;
+        let mut buf = AlignedBuf::new(end - start);
+        self.image.read_exact_at(&mut buf, self.offset + start as u64)?;
+        Ok((buf, range.start - start))
+    }
+}
+
+impl RpmbStore for DirectStore {
+    fn capacity(&self) -> u8 {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        let range = block_range(address, count as usize * RPMB_BLOCK_SIZE, self.len as usize)?;
+        let (buf, skip) = self.read_sectors(&range)?;
+        Ok(buf[skip..skip + range.len()].to_vec())
+    }
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        if self.read_only {
+            return Err(Error::new(ErrorKind::PermissionDenied, "Image is read-only"));
+        }
+        let range = block_range(address, data.len(), self.len as usize)?;
+
+        let _write = self.write_lock.lock_or_recover();
+        let (mut buf, skip) = self.read_sectors(&range)?;
+        buf[skip..skip + data.len()].copy_from_slice(data);
+        self.image.write_all_at(&buf, self.offset + (range.start - skip) as u64)
+    }
+
+    /*
+     * Every write is already on the disk.
+     */
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/*
+ * Every write goes to a second image file as well as the primary
+ * store, as a hot copy or to catch corruption. The mirror is best
+ * effort unless strict, in which case its failures are reported to
+ * the guest. With verify every read is compared against it.
+ */
+#[derive(Debug)]
+pub struct MirrorStore {
+    primary: Box<dyn RpmbStore>,
+    mirror: FileStore,
+    strict: bool,
+    verify: bool,
+}
+
+impl MirrorStore {
+    /*
+     * Open the mirror at path, creating it as a copy of the primary
+     * if it doesn't exist yet. An existing mirror must be the same
+     * size as the primary.
+     */
+    pub fn open(primary: Box<dyn RpmbStore>, path: &Path, strict: bool, verify: bool)
+                -> Result<MirrorStore> {
+        let capacity = primary.capacity();
+        let read_only = primary.is_read_only();
+
+        let mirror = if path.exists() {
+            let mirror = FileStore::open(path, read_only, None)?;
+            if mirror.capacity() != capacity {
+                return Err(Error::new(ErrorKind::InvalidInput,
+                                      format!("mirror {} is {} x 128KB, expected {}",
+                                              path.display(), mirror.capacity(), capacity)));
+            }
+            mirror
+        } else {
+            OpenOptions::new().write(true).create_new(true).open(path)?
+                .set_len(capacity as u64 * UNIT_128KB)?;
+            let mirror = FileStore::open(path, false, None)?;
+            /* A whole device is one block too many to address in one go */
+            let unit_blocks = (UNIT_128KB / RPMB_BLOCK_SIZE as u64) as u16;
+            for unit in 0..capacity as u16 {
+                let address = unit * unit_blocks;
+                mirror.write_blocks(address, &primary.read_blocks(address, unit_blocks)?)?;
+            }
+            info!("created mirror {}", path.display());
+            mirror
+        };
+
+        Ok(MirrorStore { primary, mirror, strict, verify })
+    }
+
+    fn mirror_result(&self, what: &str, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e) if self.strict => Err(e),
+            Err(e) => {
+                warn!("mirror {} failed: {}", what, e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+}
+
+impl RpmbStore for MirrorStore {
+    fn capacity(&self) -> u8 {
+        self.primary.capacity()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.primary.is_read_only()
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        let data = self.primary.read_blocks(address, count)?;
+        if self.verify {
+            match self.mirror.read_blocks(address, count) {
+                Ok(mirrored) if mirrored == data => (),
+                Ok(_) => warn!("mirror differs from the image in blocks {:#x}+{}", address, count),
+                Err(e) => warn!("mirror read failed: {}", e),
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        self.primary.write_blocks(address, data)?;
+        self.mirror_result("write", self.mirror.write_blocks(address, data))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.primary.flush()?;
+        self.mirror_result("flush", self.mirror.flush())
+    }
+}
+
+/*
+ * The most recently used blocks, evicting the least recently used
+ * once full. Each use takes a new tick which orders the blocks. The
+ * generation moves on whenever the store is written or the cache
+ * dropped so a read done outside the lock can tell if it is stale.
+ */
+#[derive(Debug)]
+struct BlockCache {
+    max_blocks: usize,
+    tick: u64,
+    generation: u64,
+    blocks: HashMap<u16, (u64, Vec<u8>)>,
+    lru: BTreeMap<u64, u16>,
+}
+
+impl BlockCache {
+    fn new(max_blocks: usize) -> BlockCache {
+        BlockCache {
+            max_blocks,
+            tick: 0,
+            generation: 0,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn touch(&mut self, address: u16) -> u64 {
+        self.tick += 1;
+        if let Some((tick, _)) = self.blocks.get(&address) {
+            self.lru.remove(tick);
+        }
+        self.lru.insert(self.tick, address);
+        self.tick
+    }
+
+    fn get(&mut self, address: u16) -> Option<Vec<u8>> {
+        self.blocks.get(&address)?;
+        let tick = self.touch(address);
+        let entry = self.blocks.get_mut(&address).expect("cached block");
+        entry.0 = tick;
+        Some(entry.1.clone())
+    }
+
+    fn insert(&mut self, address: u16, block: &[u8]) {
+        if !self.blocks.contains_key(&address) && self.blocks.len() >= self.max_blocks {
+            if let Some((&oldest, &victim)) = self.lru.iter().next() {
+                self.lru.remove(&oldest);
+                self.blocks.remove(&victim);
+            }
+        }
+        let tick = self.touch(address);
+        self.blocks.insert(address, (tick, block.to_vec()));
+    }
+
+    fn remove(&mut self, address: u16) {
+        self.generation += 1;
+        if let Some((tick, _)) = self.blocks.remove(&address) {
+            self.lru.remove(&tick);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.blocks.clear();
+        self.lru.clear();
+    }
+}
+
+/*
+ * Keeps the most recently used blocks of another store in memory,
+ * worthwhile when reads would otherwise go to the disk every time as
+ * with a DirectStore. Writes go straight through to the store before
+ * completing so the cache never holds anything that isn't durable.
+ * Writes hold the cache lock over the store access but a read that
+ * misses doesn't, so other readers aren't stuck behind the disk. It
+ * only caches what it read if nothing changed the cache meanwhile,
+ * otherwise it might put back data a concurrent write just replaced.
+ */
+#[derive(Debug)]
+pub struct CacheStore {
+    store: Box<dyn RpmbStore>,
+    cache: Mutex<BlockCache>,
+    hits: AtomicU32,
+    misses: AtomicU32,
+}
+
+impl CacheStore {
+    pub fn new(store: Box<dyn RpmbStore>, max_blocks: usize) -> CacheStore {
+        CacheStore {
+            store,
+            cache: Mutex::new(BlockCache::new(max_blocks)),
+            hits: AtomicU32::new(0),
+            misses: AtomicU32::new(0),
+        }
+    }
+}
+
+impl RpmbStore for CacheStore {
+    fn capacity(&self) -> u8 {
+        self.store.capacity()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.store.is_read_only()
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        let generation = {
+            let mut cache = self.cache.lock_or_recover();
+            let cached: Vec<_> = (0..count)
+                .map(|i| address.checked_add(i).and_then(|a| cache.get(a)))
+                .collect();
+            let hits = cached.iter().filter(|b| b.is_some()).count() as u32;
+            self.hits.fetch_add(hits, Ordering::Relaxed);
+            if hits == count as u32 {
+                return Ok(cached.into_iter().flatten().flatten().collect());
+            }
+            self.misses.fetch_add(count as u32 - hits, Ordering::Relaxed);
+            cache.generation
+        };
+
+        /* One read for the lot is cheaper than one per missing block */
+        let data = self.store.read_blocks(address, count)?;
+
+        let mut cache = self.cache.lock_or_recover();
+        if cache.generation == generation {
+            for (i, block) in data.chunks(RPMB_BLOCK_SIZE).enumerate() {
+                cache.insert(address + i as u16, block);
+            }
+        }
+        Ok(data)
+    }
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        let mut cache = self.cache.lock_or_recover();
+        cache.generation += 1;
+        let result = self.store.write_blocks(address, data);
+        for (i, block) in data.chunks(RPMB_BLOCK_SIZE).enumerate() {
+            let block_address = address.wrapping_add(i as u16);
+            match result {
+                Ok(()) => cache.insert(block_address, block),
+                /* No telling what made it to the store */
+                Err(_) => cache.remove(block_address),
+            }
+        }
+        result
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.store.flush()
+    }
+
+    fn refresh_capacity(&self) -> Result<u8> {
+        let mut cache = self.cache.lock_or_recover();
+        let capacity = self.store.refresh_capacity()?;
+        cache.clear();
+        Ok(capacity)
+    }
+
+    #[cfg(feature = "grow")]
+    fn grow(&self, size: u64) -> Result<u8> {
+        let mut cache = self.cache.lock_or_recover();
+        let capacity = self.store.grow(size)?;
+        cache.clear();
+        Ok(capacity)
+    }
+
+    fn cache_stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/*
+ * A store that only lives in memory, nothing touches the disk.
+ */
+#[cfg(feature = "mem-store")]
+#[derive(Debug)]
+pub struct MemStore {
+    data: RwLock<Vec<u8>>,
+    capacity: u8,
+}
+
+#[cfg(feature = "mem-store")]
+impl MemStore {
+    pub fn new(capacity: u8) -> MemStore {
+        MemStore {
+            data: RwLock::new(vec![0; capacity as usize * UNIT_128KB as usize]),
+            capacity,
+        }
+    }
+}
+
+#[cfg(feature = "mem-store")]
+impl RpmbStore for MemStore {
+    fn capacity(&self) -> u8 {
+        self.capacity
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        let data = self.data.read_or_recover();
+        let range = block_range(address, count as usize * RPMB_BLOCK_SIZE, data.len())?;
+        Ok(data[range].to_vec())
+    }
+
+    fn write_blocks(&self, address: u16, blocks: &[u8]) -> Result<()> {
+        let mut data = self.data.write_or_recover();
+        let range = block_range(address, blocks.len(), data.len())?;
+        data[range].copy_from_slice(blocks);
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
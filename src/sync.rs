@@ -0,0 +1,41 @@
+/*
+ * Lock helpers
+ *
+ * A thread that panics while holding a lock poisons it. For a long
+ * running daemon that shouldn't make every later request panic too,
+ * the state behind our locks is always left consistent enough to
+ * carry on with so we take the guard back and log that we did.
+ */
+
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tracing::warn;
+
+fn recover<G>(e: PoisonError<G>) -> G {
+    warn!("recovering a lock poisoned by a panic");
+    e.into_inner()
+}
+
+pub trait LockRecover<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T>;
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T>;
+}
+
+impl<T> LockRecover<T> for RwLock<T> {
+    fn read_or_recover(&self) -> RwLockReadGuard<'_, T> {
+        self.read().unwrap_or_else(recover)
+    }
+
+    fn write_or_recover(&self) -> RwLockWriteGuard<'_, T> {
+        self.write().unwrap_or_else(recover)
+    }
+}
+
+pub trait MutexRecover<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> MutexRecover<T> for Mutex<T> {
+    fn lock_or_recover(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(recover)
+    }
+}
@@ -4,31 +4,38 @@
  * This encapsulates all vhost user message handling.
  */
 use crate::rpmb::*;
+use std::convert::TryFrom;
 use std::mem::size_of;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::{convert, error, fmt, io};
 use core::fmt::Debug;
 use arrayvec::ArrayVec;
-use log::{info, trace, warn, error};
+use zeroize::{Zeroize, Zeroizing};
+use tracing::{debug, debug_span, info, trace, warn, error};
 
 use vhost::vhost_user::message::*;
 use vhost_user_backend::{VhostUserBackend, Vring};
-use virtio_bindings::bindings::virtio_net::{
-    VIRTIO_F_VERSION_1, VIRTIO_F_NOTIFY_ON_EMPTY
-};
+use virtio_bindings::bindings::virtio_net::VIRTIO_F_VERSION_1;
 use virtio_bindings::bindings::virtio_ring::{
     VIRTIO_RING_F_EVENT_IDX, VIRTIO_RING_F_INDIRECT_DESC,
 };
-use vm_memory::{Be16, Be32, Bytes, ByteValued, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_memory::{Be16, Be32, Bytes, ByteValued, GuestAddress, GuestMemory, GuestMemoryAtomic,
+                GuestMemoryLoadGuard, GuestMemoryMmap};
+use vm_virtio::{DescriptorChain, Queue};
+use vmm_sys_util::eventfd::{EventFd, EFD_NONBLOCK};
 
 use crate::rpmb::RpmbBackend;
+use crate::crypto::MacAlgorithm;
+use crate::profile::{LatencyStats, RequestProfile};
+use crate::record::Recorder;
+use crate::sync::{LockRecover, MutexRecover};
 
 type Result<T> = std::result::Result<T, Error>;
 type VhostUserBackendResult<T> = std::result::Result<T, std::io::Error>;
-
-use sha2::Sha256;
-use hmac::{Hmac, NewMac};
-type HmacSha256 = Hmac<sha2::Sha256>;
+pub type RpmbDescriptorChain = DescriptorChain<GuestMemoryLoadGuard<GuestMemoryMmap>>;
+pub type RpmbQueue = Queue<GuestMemoryAtomic<GuestMemoryMmap>>;
 
 #[derive(Debug)]
 /// Errors related to vhost-user-rpmb daemon.
@@ -47,18 +54,57 @@ pub enum Error {
     UnexpectedDescriptorSize,
     /// Descriptor not found
     DescriptorNotFound,
+    /// Descriptor address is not in guest memory
+    DescriptorNotMapped(u64),
     /// Descriptor read failed
     DescriptorReadFailed,
     /// Descriptor write failed
     DescriptorWriteFailed,
     /// Descriptor send failed
     DescriptorSendFailed,
+    /// Couldn't change the queue's notification state
+    NotificationFailed,
+    /// Resetting the device state failed
+    ResetFailed,
+    /// Queue size is not a power of two within virtio limits
+    InvalidQueueSize,
+    /// Need at least one queue
+    InvalidNumQueues,
+    /// Failed to create the exit eventfd
+    EventFdFailed,
+    /// Max write blocks must be at least one
+    InvalidMaxWriteBlocks,
+    /// The guest tried to write to the config space
+    ConfigReadOnly,
 }
 impl error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "vhost-device-i2c error: {:?}", self)
+        let msg = match self {
+            Error::HandleEventNotEpollIn => "event is not an EPOLLIN event",
+            Error::HandleEventUnknownEvent => "unknown device event",
+            Error::UnexpectedWriteOnlyDescriptor => "request frame is in a write only descriptor",
+            Error::UnexpectedReadDescriptor => "no writeable descriptor for the response",
+            Error::UnexpectedDescriptorCount => "descriptor chain is too short",
+            Error::UnexpectedDescriptorSize => "descriptor is not the size of an RPMB frame",
+            Error::DescriptorNotFound => "descriptor not found in the queue",
+            Error::DescriptorNotMapped(addr) => {
+                return write!(f, "vhost-user-rpmb error: descriptor address {:#x} is not in guest memory",
+                              addr);
+            }
+            Error::DescriptorReadFailed => "failed to read a frame from guest memory",
+            Error::DescriptorWriteFailed => "failed to write a frame to guest memory",
+            Error::DescriptorSendFailed => "failed to return a descriptor to the guest",
+            Error::NotificationFailed => "failed to update the queue notification state",
+            Error::ResetFailed => "failed to reset the device state",
+            Error::InvalidQueueSize => "queue size must be a power of two no larger than 32768",
+            Error::InvalidNumQueues => "there must be at least one queue",
+            Error::EventFdFailed => "failed to create the exit eventfd",
+            Error::InvalidMaxWriteBlocks => "max write blocks must be at least one",
+            Error::ConfigReadOnly => "the config space is read-only",
+        };
+        write!(f, "vhost-user-rpmb error: {}", msg)
     }
 }
 
@@ -71,14 +117,56 @@ impl convert::From<Error> for io::Error {
 #[derive(Debug)]
 pub struct VhostUserRpmb {
     backend: RpmbBackend,
+    pending: RwLock<Option<ResultReqResp>>,
+    num_queues: usize,
+    queue_size: usize,
+    max_write_blocks: u8,
+    reject_zero_key: bool,
+    max_auth_failures: Option<u32>,
+    strict_nonce: bool,
+    serial: Option<[u8; SERIAL_LEN]>,
+    disabled_features: u64,
+    acked_features: u64,
     event_idx: bool,
-    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>
+    profile: Option<Mutex<RequestProfile>>,
+    recorder: Option<Recorder>,
+    response_delay: Option<Duration>,
+    mem: Option<GuestMemoryAtomic<GuestMemoryMmap>>,
+    pub exit_event: EventFd,
+}
+
+// Defaults for the queue geometry
+pub const QUEUE_SIZE: usize = 1024;
+pub const NUM_QUEUES: usize = 1;
+const MAX_QUEUE_SIZE: usize = 32768;
+// Any request needs a request and a response descriptor
+const MIN_QUEUE_SIZE: usize = 2;
+// Conservative limit on the blocks in a single authenticated write
+const DEFAULT_MAX_WRITE_BLOCKS: u8 = 1;
+
+fn valid_queue_size(queue_size: usize) -> bool {
+    queue_size.is_power_of_two() && (MIN_QUEUE_SIZE..=MAX_QUEUE_SIZE).contains(&queue_size)
+}
+
+/*
+ * The virtio-rpmb config space, as read by the guest.
+ */
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C, packed)]
+pub struct VirtioRpmbConfig {
+    pub capacity: u8,
+    pub max_wr_cnt: u8,
+    pub max_rd_cnt: u8,
 }
 
-// The device has been dropped.
-// const KILL_EVENT: u16 = 2;
-const QUEUE_SIZE: usize = 1024;
-const NUM_QUEUES: usize = 1;
+unsafe impl ByteValued for VirtioRpmbConfig {}
+
+/*
+ * An optional serial follows the standard config, NUL padded like
+ * the virtio-blk ID. It isn't part of the spec so guests need to
+ * know to look for it.
+ */
+pub const SERIAL_LEN: usize = 20;
 
 /*
  * Rpmb Message Parsing
@@ -92,15 +180,70 @@ const NUM_QUEUES: usize = 1;
 */
 pub const VIRTIO_RPMB_REQ_PROGRAM_KEY:  u16 = 0x0001;
 pub const VIRTIO_RPMB_REQ_GET_WRITE_COUNTER: u16 = 0x0002;
+pub const VIRTIO_RPMB_REQ_DATA_WRITE:   u16 = 0x0003;
+pub const VIRTIO_RPMB_REQ_DATA_READ:    u16 = 0x0004;
 pub const VIRTIO_RPMB_REQ_RESULT_READ:  u16 = 0x0005;
 
 pub const VIRTIO_RPMB_RESP_PROGRAM_KEY: u16 = 0x0100;
 pub const VIRTIO_RPMB_RESP_GET_COUNTER: u16 = 0x0200;
+pub const VIRTIO_RPMB_RESP_DATA_WRITE:  u16 = 0x0300;
+pub const VIRTIO_RPMB_RESP_DATA_READ:   u16 = 0x0400;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RequestType {
     ProgramKey,
-    Unsupported(u32),
+    GetWriteCounter,
+    DataWrite,
+    DataRead,
+    ResultRead,
+    Unsupported(u16),
+}
+
+/*
+ * Only the codes we know about parse, anything else is handed back
+ * so the caller can decide what to do with it. The dispatch treats
+ * it as Unsupported.
+ */
+impl TryFrom<u16> for RequestType {
+    type Error = u16;
+
+    fn try_from(req_resp: u16) -> std::result::Result<Self, u16> {
+        match req_resp {
+            VIRTIO_RPMB_REQ_PROGRAM_KEY => Ok(RequestType::ProgramKey),
+            VIRTIO_RPMB_REQ_GET_WRITE_COUNTER => Ok(RequestType::GetWriteCounter),
+            VIRTIO_RPMB_REQ_DATA_WRITE => Ok(RequestType::DataWrite),
+            VIRTIO_RPMB_REQ_DATA_READ => Ok(RequestType::DataRead),
+            VIRTIO_RPMB_REQ_RESULT_READ => Ok(RequestType::ResultRead),
+            _ => Err(req_resp),
+        }
+    }
+}
+
+impl RequestType {
+    /*
+     * The req_resp code of the request, the inverse of try_from.
+     */
+    pub fn code(&self) -> u16 {
+        match self {
+            RequestType::ProgramKey => VIRTIO_RPMB_REQ_PROGRAM_KEY,
+            RequestType::GetWriteCounter => VIRTIO_RPMB_REQ_GET_WRITE_COUNTER,
+            RequestType::DataWrite => VIRTIO_RPMB_REQ_DATA_WRITE,
+            RequestType::DataRead => VIRTIO_RPMB_REQ_DATA_READ,
+            RequestType::ResultRead => VIRTIO_RPMB_REQ_RESULT_READ,
+            RequestType::Unsupported(req_resp) => *req_resp,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            RequestType::ProgramKey => "program_key",
+            RequestType::GetWriteCounter => "get_write_counter",
+            RequestType::DataWrite => "data_write",
+            RequestType::DataRead => "data_read",
+            RequestType::ResultRead => "result_read",
+            RequestType::Unsupported(_) => "unsupported",
+        }
+    }
 }
 
 // #define VIRTIO_RPMB_RES_OK                     0x0000
@@ -114,37 +257,70 @@ pub enum RequestType {
 // #define VIRTIO_RPMB_RES_WRITE_COUNTER_EXPIRED  0x0080
 pub const VIRTIO_RPMB_RES_OK: u16 = 0x0000;
 pub const VIRTIO_RPMB_RES_GENERAL_FAILURE: u16 = 0x0001;
+pub const VIRTIO_RPMB_RES_AUTH_FAILURE: u16 = 0x0002;
+pub const VIRTIO_RPMB_RES_COUNT_FAILURE: u16 = 0x0003;
+pub const VIRTIO_RPMB_RES_ADDR_FAILURE: u16 = 0x0004;
 pub const VIRTIO_RPMB_RES_WRITE_FAILURE: u16 = 0x0005;
+pub const VIRTIO_RPMB_RES_READ_FAILURE: u16 = 0x0006;
 pub const VIRTIO_RPMB_RES_NO_AUTH_KEY: u16 = 0x0007;
+pub const VIRTIO_RPMB_RES_WRITE_COUNTER_EXPIRED: u16 = 0x0080;
 
 pub enum RequestResultType {
     Ok,
     GeneralFailure
 }
 
+/*
+ * The outcome of a PROGRAM_KEY or DATA_WRITE, held until the guest
+ * asks for it. The counter is captured when the request completes so
+ * the result reflects the write it is reporting on.
+ */
 #[derive(Debug)]
-struct ResultReqResp(u16, u16);
+struct ResultReqResp {
+    req_resp: u16,
+    result: u16,
+    write_counter: u32,
+    address: u16,
+    block_count: u16,
+}
 
 #[derive(Debug)]
 enum RequestResponse {
     NoResponse,
     PendingResponse { req_resp: u16, result: u16 },
-    Response(VirtIORPMBFrame)
+    Response(VirtIORPMBFrame),
+    MultiResponse(Vec<VirtIORPMBFrame>)
 }
 
 
+/*
+ * Who fills in what:
+ *
+ *   field          PROGRAM_KEY  GET_COUNTER  DATA_WRITE  DATA_READ
+ *   key_mac        guest        device       guest       device
+ *   data           -            -            guest       device
+ *   nonce          -            guest        -           guest
+ *   write_counter  -            device       guest       -
+ *   address        -            -            guest       guest
+ *   block_count    guest        -            guest       guest
+ *   result         device       device       device      device
+ *   req_resp       guest        guest        guest       guest
+ *
+ * The device fills the response req_resp and echoes the nonce. The
+ * result field of a request frame must be zero.
+ */
 #[derive(Copy, Clone)]
 #[repr(C, packed)]
-struct VirtIORPMBFrame {
+pub(crate) struct VirtIORPMBFrame {
     stuff: [u8; 196],
-    key_mac: [u8; RPMB_KEY_MAC_SIZE],
+    pub(crate) key_mac: [u8; RPMB_KEY_MAC_SIZE],
     data: [u8; RPMB_BLOCK_SIZE],
-    nonce: [u8; 16],
+    pub(crate) nonce: [u8; 16],
     write_counter: Be32,
-    address: Be16,
-    block_count: Be16,
-    result: Be16,
-    req_resp: Be16
+    pub(crate) address: Be16,
+    pub(crate) block_count: Be16,
+    pub(crate) result: Be16,
+    pub(crate) req_resp: Be16
 }
 
 /*
@@ -186,14 +362,63 @@ impl Debug for VirtIORPMBFrame {
 
 unsafe impl ByteValued for VirtIORPMBFrame {}
 
-/* Implement some frame builders for sending our results back */
+/*
+ * A PROGRAM_KEY frame carries the raw key, so copies of request
+ * frames are scrubbed once we are done with them.
+ */
+impl Zeroize for VirtIORPMBFrame {
+    fn zeroize(&mut self) {
+        self.as_mut_slice().zeroize();
+    }
+}
+
+/*
+ * The fields of a frame covered by its MAC, so tools can work out
+ * the MAC the device expects without building a frame themselves.
+ */
+#[derive(Clone, Debug)]
+pub struct MacInput {
+    pub data: [u8; RPMB_BLOCK_SIZE],
+    pub nonce: [u8; 16],
+    pub write_counter: u32,
+    pub address: u16,
+    pub block_count: u16,
+    pub result: u16,
+    pub req_resp: u16,
+}
+
+impl MacInput {
+    /*
+     * The MAC of a single frame with these fields, calculated exactly
+     * as the device does.
+     */
+    pub fn mac(&self, algorithm: MacAlgorithm, key: &[u8]) -> [u8; RPMB_KEY_MAC_SIZE] {
+        let frame = VirtIORPMBFrame {
+            data: self.data,
+            nonce: self.nonce,
+            write_counter: From::from(self.write_counter),
+            address: From::from(self.address),
+            block_count: From::from(self.block_count),
+            result: From::from(self.result),
+            req_resp: From::from(self.req_resp),
+            ..Default::default()
+        };
+        algorithm.compute(key, &[frame])
+    }
+}
+
+/*
+ * Implement some frame builders for sending our results back. The
+ * nonce is echoed from the request, requests without one (e.g.
+ * DATA_WRITE) pass zeros.
+ */
 impl VirtIORPMBFrame {
-    fn result(req_resp:u16, result: u16) -> Self {
+    fn result(req_resp:u16, result: u16, nonce: [u8; 16]) -> Self {
         VirtIORPMBFrame {
             stuff: [0; 196],
             key_mac: [0; RPMB_KEY_MAC_SIZE],
             data: [0; RPMB_BLOCK_SIZE],
-            nonce: [0; 16],
+            nonce,
             write_counter: From::from(0),
             address: From::from(0),
             block_count: From::from(0),
@@ -202,42 +427,258 @@ impl VirtIORPMBFrame {
          }
     }
 
-    fn calculate_mac(&mut self, mut mac: Hmac<Sha256>) -> VirtIORPMBFrame {
-        use hmac::Mac;
-        // const len: usize = size_of::<VirtIORPMBFrame>() - 196 - RPMB_KEY_MAC_SIZE;
-        // let data: [u8; len] = bytemuck::cast(self.data[0]);
-        mac.update(&self.data);
-        mac.update(&self.nonce);
-        // we have to grab the remaining fields using as_slice() to
-        // get the raw bytes in order.
-        mac.update(self.write_counter.as_slice());
-        mac.update(&self.address.as_slice());
-        mac.update(&self.block_count.as_slice());
-        mac.update(&self.result.as_slice());
-        mac.update(&self.req_resp.as_slice());
-
-        let result = mac.finalize().into_bytes();
-        self.key_mac = result.into();
+    /*
+     * The frame returned by RESULT_READ for a pending result.
+     */
+    fn pending_result(pending: &ResultReqResp) -> Self {
+        let mut frame = VirtIORPMBFrame::result(pending.req_resp, pending.result, [0; 16]);
+        frame.write_counter = From::from(pending.write_counter);
+        frame.address = From::from(pending.address);
+        frame.block_count = From::from(pending.block_count);
+        frame
+    }
+
+    /*
+     * The region of the frame covered by the MAC, from data through
+     * to req_resp.
+     */
+    pub(crate) fn mac_region(&self) -> &[u8] {
+        &self.as_slice()[196 + RPMB_KEY_MAC_SIZE..]
+    }
+
+    fn calculate_mac(&mut self, algorithm: MacAlgorithm, key: &[u8]) -> VirtIORPMBFrame {
+        self.key_mac = algorithm.compute(key, &[*self]);
         *self
     }
 }
 
+/*
+ * Frame accesses to guest memory. A guest address that isn't mapped
+ * at all points at a broken memory layout rather than a failed copy
+ * so we report it separately.
+ */
+fn check_mapped<M: GuestMemory>(mem: &M, addr: GuestAddress) -> Result<()> {
+    if mem.check_range(addr, size_of::<VirtIORPMBFrame>()) {
+        Ok(())
+    } else {
+        error!("frame at {:#x} is outside guest memory", addr.0);
+        Err(Error::DescriptorNotMapped(addr.0))
+    }
+}
+
+fn read_frame<M: GuestMemory>(mem: &M, addr: GuestAddress) -> Result<VirtIORPMBFrame> {
+    check_mapped(mem, addr)?;
+    mem.read_obj::<VirtIORPMBFrame>(addr)
+        .map_err(|_| Error::DescriptorReadFailed)
+}
+
+fn write_frame<M: GuestMemory>(mem: &M, frame: VirtIORPMBFrame, addr: GuestAddress) -> Result<()> {
+    check_mapped(mem, addr)?;
+    mem.write_obj::<VirtIORPMBFrame>(frame, addr)
+        .map_err(|_| Error::DescriptorWriteFailed)
+}
+
 /*
  * Core VhostUserRpmb methods
  */
 impl VhostUserRpmb {
     pub fn new(backend: RpmbBackend) -> Result<Self> {
+        VhostUserRpmb::with_queues(backend, NUM_QUEUES, QUEUE_SIZE)
+    }
+
+    /*
+     * A device with num_queues request queues of queue_size entries.
+     * virtio-rpmb only defines one but every queue is handled the
+     * same way if a frontend wants more.
+     */
+    pub fn with_queues(backend: RpmbBackend, num_queues: usize, queue_size: usize) -> Result<Self> {
+        if num_queues == 0 || num_queues >= u16::MAX as usize {
+            return Err(Error::InvalidNumQueues);
+        }
+        if !valid_queue_size(queue_size) {
+            return Err(Error::InvalidQueueSize);
+        }
+
         Ok(VhostUserRpmb
            {
                backend,
+               pending: RwLock::new(None),
+               num_queues,
+               queue_size,
+               max_write_blocks: DEFAULT_MAX_WRITE_BLOCKS,
+               reject_zero_key: false,
+               max_auth_failures: None,
+               strict_nonce: false,
+               serial: None,
+               disabled_features: 0,
+               acked_features: 0,
                event_idx: false,
-               mem: None
+               profile: None,
+               recorder: None,
+               response_delay: None,
+               mem: None,
+               exit_event: EventFd::new(EFD_NONBLOCK).map_err(|_| Error::EventFdFailed)?,
            })
     }
 
-    fn program_key(&self, frame: VirtIORPMBFrame) -> RequestResponse {
+    /*
+     * Queue kicks are device events 0..num_queues, the exit event
+     * goes after them leaving a gap as it always has.
+     */
+    fn kill_event(&self) -> u16 {
+        self.num_queues as u16 + 1
+    }
+
+    /*
+     * The queue size must be a power of two no larger than the
+     * virtio maximum, and big enough for at least one request.
+     */
+    pub fn set_queue_size(&mut self, queue_size: usize) -> Result<()> {
+        if !valid_queue_size(queue_size) {
+            return Err(Error::InvalidQueueSize);
+        }
+        self.queue_size = queue_size;
+        Ok(())
+    }
+
+    /*
+     * Limit how many blocks a single authenticated write can carry,
+     * like the reliable write sector count of a real eMMC part.
+     */
+    pub fn set_max_write_blocks(&mut self, max_write_blocks: u8) -> Result<()> {
+        if max_write_blocks == 0 {
+            return Err(Error::InvalidMaxWriteBlocks);
+        }
+        self.max_write_blocks = max_write_blocks;
+        Ok(())
+    }
+
+    /*
+     * An all zero key is almost certainly a guest bug rather than a
+     * deliberate choice, optionally refuse to be locked to one.
+     */
+    pub fn set_reject_zero_key(&mut self, reject: bool) {
+        self.reject_zero_key = reject;
+    }
+
+    /*
+     * Emulate hardware that locks out writes after too many bad
+     * MACs, to stop the key being brute forced. Only a reset lifts
+     * the lockout.
+     */
+    pub fn set_max_auth_failures(&mut self, max_auth_failures: u32) {
+        self.max_auth_failures = Some(max_auth_failures);
+    }
+
+    /*
+     * Fail reads (DATA_READ and GET_WRITE_COUNTER) that reuse one of
+     * the last NONCE_WINDOW nonces. The spec only asks the guest for
+     * a fresh nonce, it doesn't require the device to check, so this
+     * is off by default. It emulates stricter parts to shake out
+     * drivers that reuse nonces.
+     */
+    pub fn set_strict_nonce(&mut self, strict: bool) {
+        self.strict_nonce = strict;
+    }
+
+    fn nonce_reused(&self, nonce: [u8; 16]) -> bool {
+        if self.strict_nonce && !self.backend.record_nonce(nonce) {
+            warn!("nonce {:x?} reused", nonce);
+            return true;
+        }
+        false
+    }
+
+    fn locked_out(&self) -> bool {
+        self.max_auth_failures
+            .map_or(false, |max| self.backend.stats().auth_failures >= max)
+    }
+
+    /*
+     * Time every request, see RequestProfile. Off by default so
+     * normal runs don't pay for the clock reads.
+     */
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Mutex::new(RequestProfile::default()));
+    }
+
+    /*
+     * Wait this long before writing each response frame, to see how
+     * a guest copes with slow hardware. Purely for testing guests.
+     */
+    pub fn set_response_delay(&mut self, delay: Duration) {
+        self.response_delay = Some(delay).filter(|d| *d > Duration::from_millis(0));
+    }
+
+    /*
+     * Log every chain we handle so it can be replayed later, see
+     * crate::record.
+     */
+    pub fn set_recorder(&mut self, recorder: Recorder) {
+        self.recorder = Some(recorder);
+    }
+
+    /*
+     * Per request type timings, if profiling is enabled.
+     */
+    pub fn latency(&self) -> Option<Vec<(&'static str, LatencyStats)>> {
+        self.profile.as_ref().map(|p| p.lock_or_recover().snapshot())
+    }
+
+    /*
+     * Set the serial reported after the config, anything beyond
+     * SERIAL_LEN bytes is dropped.
+     */
+    pub fn set_serial(&mut self, serial: &str) {
+        let mut id = [0; SERIAL_LEN];
+        let len = serial.len().min(SERIAL_LEN);
+        if len < serial.len() {
+            warn!("truncating serial {} to {} bytes", serial, SERIAL_LEN);
+        }
+        id[..len].copy_from_slice(&serial.as_bytes()[..len]);
+        self.serial = Some(id);
+    }
+
+    /*
+     * Allow features to be turned off to debug interop problems
+     */
+    pub fn disable_indirect_desc(&mut self) {
+        self.disabled_features |= 1 << VIRTIO_RING_F_INDIRECT_DESC;
+    }
+
+    pub fn disable_event_idx(&mut self) {
+        self.disabled_features |= 1 << VIRTIO_RING_F_EVENT_IDX;
+    }
+
+    /*
+     * A key is programmed by a single frame. If the guest sends
+     * several in one go only the first is acted on, the rest are
+     * ignored rather than failing against the key we just set and
+     * overwriting the pending result.
+     *
+     * The frame carries the key in place of a MAC so there is nothing
+     * to authenticate, but a key can never be taken back so anything
+     * that doesn't look like a PROGRAM_KEY is refused before we store
+     * it: exactly one block and no address, counter or nonce.
+     */
+    fn program_key(&self, frames: &[VirtIORPMBFrame]) -> RequestResponse {
+        let frame = &frames[0];
+        if frames.len() > 1 {
+            warn!("ignoring {} extra program key frames", frames.len() - 1);
+        }
+        self.backend.record_program_key_request();
+
         let result = if frame.block_count.to_native() != 1 {
-           VIRTIO_RPMB_RES_GENERAL_FAILURE
+            warn!("program key with block count {}", frame.block_count.to_native());
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if frame.address.to_native() != 0
+            || frame.write_counter.to_native() != 0
+            || frame.nonce != [0; 16] {
+            warn!("program key frame has an address, counter or nonce set");
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if self.reject_zero_key && frame.key_mac.iter().all(|&b| b == 0) {
+            warn!("rejecting an all zero key");
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
         } else {
             match self.backend.program_key(ArrayVec::from(frame.key_mac)) {
                 Ok(_) => {
@@ -251,167 +692,632 @@ impl VhostUserRpmb {
         RequestResponse::PendingResponse{req_resp: VIRTIO_RPMB_RESP_PROGRAM_KEY, result}
     }
 
+    /*
+     * Reject requests where any frame has something in the device
+     * filled result field, a conforming guest always leaves it zero.
+     * RESULT_READ carries nothing we act on so is never rejected.
+     */
+    fn check_reserved(&self, request_type: RequestType, request: &[VirtIORPMBFrame])
+                      -> Option<RequestResponse> {
+        if request.iter().all(|f| f.result.to_native() == 0) {
+            return None;
+        }
+        warn!("request frame with non-zero result field");
+        self.reject(request_type, request)
+    }
+
+    /*
+     * Fail a request with GENERAL_FAILURE without acting on it.
+     */
+    fn reject(&self, request_type: RequestType, request: &[VirtIORPMBFrame])
+              -> Option<RequestResponse> {
+        let result = VIRTIO_RPMB_RES_GENERAL_FAILURE;
+        let nonce = request[0].nonce;
+        match request_type {
+            RequestType::ProgramKey => Some(RequestResponse::PendingResponse{
+                req_resp: VIRTIO_RPMB_RESP_PROGRAM_KEY, result}),
+            RequestType::DataWrite => Some(RequestResponse::PendingResponse{
+                req_resp: VIRTIO_RPMB_RESP_DATA_WRITE, result}),
+            RequestType::GetWriteCounter => Some(RequestResponse::Response(
+                VirtIORPMBFrame::result(VIRTIO_RPMB_RESP_GET_COUNTER, result, nonce))),
+            RequestType::DataRead => Some(RequestResponse::Response(
+                VirtIORPMBFrame::result(VIRTIO_RPMB_RESP_DATA_READ, result, nonce))),
+            RequestType::ResultRead | RequestType::Unsupported(_) => None,
+        }
+    }
+
     /*
      * Run the checks from:
      * 5.12.6.1.2 Device Requirements: Device Operation: Get Write Counter
      */
     fn get_write_counter(&self, frame: VirtIORPMBFrame) -> RequestResponse {
         let req_resp = VIRTIO_RPMB_RESP_GET_COUNTER;
-        let key = self.backend.get_key();
 
-        if key.is_err() {
-            warn!("no key programmed: {:?}", key);
-            return
-                RequestResponse::Response(
-                    VirtIORPMBFrame::result(req_resp, VIRTIO_RPMB_RES_NO_AUTH_KEY));
+        /*
+         * The counter and nonce are always reported, even without a
+         * key, the result code tells the guest if it can trust them.
+         */
+        let result = if !self.backend.has_key() {
+            warn!("no key programmed");
+            VIRTIO_RPMB_RES_NO_AUTH_KEY
         } else if frame.block_count.to_native() > 1 {  /* allow 0 (NONCONF) */
-                                                          warn!("invalid
-            block count {}", frame.block_count.to_native());
-            return
-                RequestResponse::Response(
-                    VirtIORPMBFrame::result(req_resp, VIRTIO_RPMB_RES_GENERAL_FAILURE));
-        }
+            warn!("invalid block count {}", frame.block_count.to_native());
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if self.nonce_reused(frame.nonce) {
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else {
+            VIRTIO_RPMB_RES_OK
+        };
 
-        /* A proper response needs a frame with calculated MAC */
-        let mut resp = VirtIORPMBFrame::result(req_resp, VIRTIO_RPMB_RES_OK);
+        let mut resp = VirtIORPMBFrame::result(req_resp, result, frame.nonce);
         resp.write_counter = From::from(self.backend.get_write_count());
-        resp.nonce = frame.nonce;
-        let mut mac = HmacSha256::new_from_slice(&key.unwrap())
-            .expect("HMAC can take key of any size");
 
-        RequestResponse::Response(resp.calculate_mac(mac))
+        /* A proper response needs a frame with calculated MAC */
+        match self.backend.get_key() {
+            Ok(key) => RequestResponse::Response(resp.calculate_mac(self.backend.mac_algorithm(), &key[..])),
+            Err(_) => RequestResponse::Response(resp)
+        }
     }
-    
+
     /*
-     * Process the messages in the vring and dispatch replies
+     * Write the data from a sequence of frames into the backing store.
+     * Every frame carries one block, the MAC covering all of them is
+     * in the last frame. The result is queried later by the guest
+     * with a VIRTIO_RPMB_REQ_RESULT_READ.
      */
-    fn process_queue(&self, vring: &mut Vring) -> Result<bool> {
-        // let mut reqs: Vec<VirtIORPMBFrame> = Vec::new();
-        let mut pending = RequestResponse::NoResponse;
+    fn data_write(&self, frames: &[VirtIORPMBFrame]) -> RequestResponse {
+        let req_resp = VIRTIO_RPMB_RESP_DATA_WRITE;
+        let frame = frames[0];
+        let last = frames[frames.len() - 1];
+        let address = frame.address.to_native();
+        let block_count = frame.block_count.to_native();
+        self.backend.record_write_request();
+
+        if block_count == 0 {
+            warn!("write of zero blocks");
+            return RequestResponse::PendingResponse{req_resp,
+                                                    result: VIRTIO_RPMB_RES_GENERAL_FAILURE};
+        }
 
-        let requests: Vec<_> = vring
-            .mut_queue()
+        if self.backend.is_read_only() {
+            warn!("write to read-only device");
+            return RequestResponse::PendingResponse{req_resp,
+                                                    result: VIRTIO_RPMB_RES_WRITE_FAILURE};
+        }
+
+        if !self.backend.has_key() {
+            warn!("no key programmed");
+            return RequestResponse::PendingResponse{req_resp,
+                                                    result: VIRTIO_RPMB_RES_NO_AUTH_KEY};
+        }
+        /* Once programmed the key can never go away */
+        let key = self.backend.get_key().expect("key programmed");
+
+        /* Hold off other writers until the counter has moved on */
+        let _writes = self.backend.lock_writes();
+
+        /*
+         * The counter is checked twice, in two different ways. The
+         * MAC is verified over the frames exactly as the guest sent
+         * them, so it covers the counter the guest claims and a
+         * counter changed after signing is an AUTH_FAILURE. Only once
+         * the frames are authentic do we compare that claim with our
+         * own counter, where a stale or replayed write is a
+         * COUNT_FAILURE. Never verify against our counter instead:
+         * that would report a replay as an auth failure and hide
+         * which check the guest actually failed.
+         *
+         * Each frame only carries a single block of data.
+         */
+        let result = if block_count > self.max_write_blocks as u16 {
+            warn!("write of {} blocks exceeds limit of {}", block_count, self.max_write_blocks);
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if block_count as usize != frames.len() {
+            warn!("block count {} doesn't match {} frames", block_count, frames.len());
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if frames.iter().any(|f| f.address.to_native() != address
+                                    || f.block_count.to_native() != block_count
+                                    || f.write_counter.to_native() != frame.write_counter.to_native()) {
+            warn!("frames of a multi-block write disagree");
+            VIRTIO_RPMB_RES_GENERAL_FAILURE
+        } else if self.locked_out() {
+            warn!("write to {:#x} refused, locked out after too many auth failures", address);
+            VIRTIO_RPMB_RES_AUTH_FAILURE
+        } else if !self.backend.mac_algorithm().verify(&key[..], frames, &last.key_mac) {
+            warn!("MAC mismatch on write to {:#x}", address);
+            let failures = self.backend.record_auth_failure();
+            if self.max_auth_failures == Some(failures) {
+                error!("{} auth failures, locking out writes until reset", failures);
+            }
+            VIRTIO_RPMB_RES_AUTH_FAILURE
+        } else if self.backend.get_write_count() == u32::MAX {
+            warn!("write counter expired");
+            VIRTIO_RPMB_RES_WRITE_COUNTER_EXPIRED
+        } else if frame.write_counter.to_native() != self.backend.get_write_count() {
+            warn!("write counter mismatch {} != {}",
+                  frame.write_counter.to_native(), self.backend.get_write_count());
+            VIRTIO_RPMB_RES_COUNT_FAILURE
+        } else if self.backend.check_range(address, block_count).is_err() {
+            warn!("write of {} blocks to {:#x} out of range", block_count, address);
+            self.backend.record_addr_failure();
+            VIRTIO_RPMB_RES_ADDR_FAILURE
+        } else {
+            let data: Vec<u8> = frames.iter().flat_map(|f| f.data.to_vec()).collect();
+            match self.backend.write_blocks(address, &data) {
+                Ok(_) => {
+                    self.backend.increment_write_count();
+                    if let Err(e) = self.backend.persist_counter() {
+                        error!("failed to persist write counter: {}", e);
+                    }
+                    VIRTIO_RPMB_RES_OK
+                }
+                Err(e) => {
+                    warn!("write to {:#x} failed: {}", address, e);
+                    VIRTIO_RPMB_RES_WRITE_FAILURE
+                }
+            }
+        };
+        RequestResponse::PendingResponse{req_resp, result}
+    }
+
+    /*
+     * Read block_count blocks from the backing store, returning one
+     * frame per block to be written into the guests buffers. The MAC
+     * covers all the frames and is placed in the last one. The guest
+     * must have supplied a response buffer for every block.
+     */
+    fn data_read(&self, frame: VirtIORPMBFrame, response_bufs: usize) -> RequestResponse {
+        let req_resp = VIRTIO_RPMB_RESP_DATA_READ;
+        let address = frame.address.to_native();
+        let block_count = frame.block_count.to_native();
+
+        let response = |result: u16| {
+            let mut resp = VirtIORPMBFrame::result(req_resp, result, frame.nonce);
+            resp.address = frame.address;
+            resp.block_count = frame.block_count;
+            resp
+        };
+
+        if block_count == 0 {
+            warn!("read of zero blocks");
+            return RequestResponse::Response(response(VIRTIO_RPMB_RES_GENERAL_FAILURE));
+        }
+
+        let key = match self.backend.get_key() {
+            Ok(key) => key,
+            Err(e) => {
+                warn!("no key programmed: {:?}", e);
+                return RequestResponse::Response(response(VIRTIO_RPMB_RES_NO_AUTH_KEY));
+            }
+        };
+
+        if block_count as usize > response_bufs {
+            warn!("block count {} exceeds {} response buffers", block_count, response_bufs);
+            return RequestResponse::Response(response(VIRTIO_RPMB_RES_GENERAL_FAILURE));
+        }
+
+        if self.nonce_reused(frame.nonce) {
+            return RequestResponse::Response(response(VIRTIO_RPMB_RES_GENERAL_FAILURE));
+        }
+
+        if self.backend.check_range(address, block_count).is_err() {
+            warn!("read of {} blocks from {:#x} out of range", block_count, address);
+            self.backend.record_addr_failure();
+            let mut resp = response(VIRTIO_RPMB_RES_ADDR_FAILURE);
+            resp.key_mac = self.backend.mac_algorithm().compute(&key[..], &[resp]);
+            return RequestResponse::Response(resp);
+        }
+
+        let mut frames: Vec<VirtIORPMBFrame> = match self.backend.read_blocks(address, block_count) {
+            Ok(data) => {
+                self.backend.record_read();
+                data.chunks(RPMB_BLOCK_SIZE)
+                    .map(|block| {
+                        let mut resp = response(VIRTIO_RPMB_RES_OK);
+                        resp.data.copy_from_slice(block);
+                        resp
+                    })
+                    .collect()
+            }
+            Err(e) => {
+                warn!("read of {} blocks from {:#x} failed: {}", block_count, address, e);
+                vec![response(VIRTIO_RPMB_RES_READ_FAILURE)]
+            }
+        };
+
+        let mac = self.backend.mac_algorithm().compute(&key[..], &frames);
+        if let Some(last) = frames.last_mut() {
+            last.key_mac = mac;
+        }
+
+        RequestResponse::MultiResponse(frames)
+    }
+
+    /*
+     * Reset the device, dropping any result the guest never read.
+     */
+    pub fn reset(&self) -> Result<()> {
+        *self.pending.write_or_recover() = None;
+        self.backend.reset().map_err(|_| Error::ResetFailed)
+    }
+
+    /*
+     * Forget everything tied to the frontend that just went away so
+     * a new one starts from a clean slate. The backend, and with it
+     * the key and write counter, carries on.
+     */
+    pub fn disconnect(&mut self) {
+        self.mem = None;
+        self.event_idx = false;
+        self.acked_features = 0;
+        *self.pending.write_or_recover() = None;
+    }
+
+    pub fn stats(&self) -> RpmbStats {
+        self.backend.stats()
+    }
+
+    pub fn backend(&self) -> &RpmbBackend {
+        &self.backend
+    }
+
+    /*
+     * Everything we have negotiated with the frontend and our stats,
+     * for an operator poking at a running daemon. The frontend
+     * doesn't tell the backend which protocol features it acked so
+     * only our offer can be shown.
+     */
+    pub fn state_report(&self) -> String {
+        let config_len = size_of::<VirtioRpmbConfig>() + self.serial.map_or(0, |_| SERIAL_LEN);
+        let config: Vec<String> = self
+            .get_config(0, config_len as u32)
             .iter()
-            .map_err(|_| Error::DescriptorNotFound)?
+            .map(|b| format!("{:02x}", b))
             .collect();
+        format!("features offered {:#018x} acked {:#018x}, protocol features offered {:?}, \
+                 event_idx {}, config [{}], stats {:?}",
+                self.features(), self.acked_features, self.protocol_features(),
+                self.event_idx, config.join(" "), self.stats())
+    }
 
-        if requests.is_empty() {
-            return Ok(true);
+    /*
+     * Flush any data written since the last flush.
+     */
+    pub fn flush(&self) -> VhostUserBackendResult<()> {
+        self.backend.flush()
+    }
+
+    /*
+     * Flush all device state to disk.
+     */
+    pub fn sync(&self) -> VhostUserBackendResult<()> {
+        self.backend.sync()
+    }
+
+    /*
+     * The config space as the guest would currently see it. The
+     * capacity is re-read from the image so it tracks any resize.
+     */
+    pub fn config(&self) -> VirtioRpmbConfig {
+        let capacity = match self.backend.refresh_capacity() {
+            Ok(capacity) => capacity,
+            Err(e) => {
+                warn!("failed to refresh capacity: {}", e);
+                self.backend.get_capacity()
+            }
+        };
+
+        /*
+         * A read needs a request frame plus one response frame per
+         * block, all of which have to fit in a single chain.
+         */
+        let max_chain = self.queue_size - 1;
+        VirtioRpmbConfig {
+            capacity,
+            max_wr_cnt: max_chain.min(self.max_write_blocks as usize) as u8,
+            max_rd_cnt: max_chain.min(u8::MAX as usize) as u8,
         }
+    }
 
+    /*
+     * Handle the frames in a single descriptor chain, writing any
+     * responses back into its writeable descriptors. Returns the
+     * number of bytes written for the used ring.
+     *
+     * Generally we expect at least two descriptors, the request
+     * itself and the descriptors for the response. The other form
+     * is a request followed by a request for a result and then
+     * the buffer for the reply.
+     */
+    pub fn process_request(&self, desc_chain: &RpmbDescriptorChain) -> Result<u32> {
         /*
-         * Iterate over the requests and handle the messages.
-         * Generally we expect at least two descriptors, the request
-         * itself and the descriptors for the response. The other form
-         * is a request followed by a request for a result and then
-         * the buffer for the reply.
+         * Walking the chain needs its own copy of the iterator, but
+         * that only clones the cursor and a memory guard, not the
+         * descriptors. Sort them straight into the two directions.
          */
-        for desc_chain in requests.clone() {
-            let buffers: Vec<_> = desc_chain.clone().collect();
-            let mut consumed = 0;
+        let (writeable, readable): (Vec<_>, Vec<_>) = desc_chain.clone().partition(|b| b.is_write_only());
+        let mut consumed = 0;
 
-            trace!("Buffers: {:x?}", &buffers);
+        trace!("Buffers: readable {:x?} writeable {:x?}", &readable, &writeable);
 
-            if buffers.len() < 2 {
-                return Err(Error::UnexpectedDescriptorCount);
+        if writeable.len() + readable.len() < 2 {
+            return Err(Error::UnexpectedDescriptorCount);
+        }
+
+        /*
+         * Collect all the incoming frames first, a multi-block
+         * request is spread over several of them.
+         */
+        let mut frames = Zeroizing::new(Vec::new());
+        for b in &readable {
+
+            /* All frames should be the same size */
+            if b.len() as usize != size_of::<VirtIORPMBFrame>() {
+                error!("Unexpected frame size: {}", b.len());
+                return Err(Error::UnexpectedDescriptorSize);
             }
 
-            let (writeable, readable): (Vec<_>, Vec<_>) = buffers.into_iter().partition(|b| b.is_write_only());
+            /* Convert the descriptor into something we can work with */
+            let frame = read_frame(desc_chain.memory(), b.addr())?;
 
-            /* Process the incoming frames */
-            for b in &readable {
+            trace!("Incoming frame: {:x?}", frame);
+            frames.push(frame);
+        }
 
-                /* All frames should be the same size */
-                if b.len() as usize != size_of::<VirtIORPMBFrame>() {
-                    error!("Unexpected frame size: {}", b.len());
-                    return Err(Error::UnexpectedDescriptorSize);
-                }
+        let buf_lens: Vec<u32> = writeable.iter().map(|b| b.len()).collect();
+        let replies = self.process_frames(&frames, &buf_lens);
 
-                /* Convert the descriptor into something we can work with */
-                let frame = desc_chain
-                    .memory()
-                    .read_obj::<VirtIORPMBFrame>(b.addr())
-                    .map_err(|_| Error::DescriptorReadFailed)?;
+        if let Some(recorder) = &self.recorder {
+            recorder.record(&frames, &buf_lens, &replies);
+        }
 
+        for (frame, result_buf) in replies?.into_iter().zip(&writeable) {
+            /*
+             * Sleeping here holds only this queue's vring lock, the
+             * request itself is done and the backend isn't locked.
+             */
+            if let Some(delay) = self.response_delay {
+                thread::sleep(delay);
+            }
+            write_frame(desc_chain.memory(), frame, result_buf.addr())?;
+            consumed += size_of::<VirtIORPMBFrame>() as u32;
+        }
 
-                let req_resp = frame.req_resp.to_native();
-                trace!("Incoming frame: {:x?} => req_resp {:x?}", frame, req_resp);
+        /*
+         * The used length is what we wrote into the guest's buffers,
+         * one whole frame per reply. A result deferred for a later
+         * RESULT_READ only counts once it is actually read.
+         */
+        Ok(consumed)
+    }
+
+    /*
+     * How many requests in the chain would change the device state,
+     * consecutive frames of one type being a single request.
+     */
+    fn state_changing_requests(frames: &[VirtIORPMBFrame]) -> usize {
+        frames
+            .iter()
+            .enumerate()
+            .filter(|(i, f)| *i == 0
+                    || frames[i - 1].req_resp.to_native() != f.req_resp.to_native())
+            .filter(|(_, f)| matches!(f.req_resp.to_native(),
+                                      VIRTIO_RPMB_REQ_PROGRAM_KEY | VIRTIO_RPMB_REQ_DATA_WRITE))
+            .count()
+    }
+
+    /*
+     * Handle the request frames of a chain which has writeable
+     * buffers of buf_lens bytes, returning the replies to fill them
+     * with in order. Nothing is written to the guest until the whole
+     * chain has been handled.
+     */
+    pub(crate) fn process_frames(&self, frames: &[VirtIORPMBFrame], buf_lens: &[u32])
+                                 -> Result<Vec<VirtIORPMBFrame>> {
+        /* Only correctly sized buffers can take a response frame */
+        let response_bufs = buf_lens
+            .iter()
+            .filter(|len| **len as usize == size_of::<VirtIORPMBFrame>())
+            .count();
+
+        /*
+         * Consecutive frames with the same req_resp make up one
+         * logical request, e.g. N DATA_WRITE frames followed by a
+         * RESULT_READ. Responses fill the writeable buffers in
+         * order.
+         */
+        let mut replies = Vec::new();
+        let mut remaining = frames;
+
+        /*
+         * A chain may change the device state at most once, with a
+         * second key or write it would be ambiguous which result the
+         * guest gets back. Such a chain is refused as a whole before
+         * any of it is acted on.
+         */
+        let ambiguous = VhostUserRpmb::state_changing_requests(frames) > 1;
+        if ambiguous {
+            warn!("more than one state changing request in a chain, rejecting all of it");
+        }
 
-                /* Dispatch request frames to their handlers */
-                let res: RequestResponse = match req_resp {
-                    VIRTIO_RPMB_REQ_PROGRAM_KEY => {
-                        self.program_key(frame)
+        while let Some(first) = remaining.first() {
+            let req_resp = first.req_resp.to_native();
+            let len = remaining
+                .iter()
+                .take_while(|f| f.req_resp.to_native() == req_resp)
+                .count();
+            let (request, rest) = remaining.split_at(len);
+            remaining = rest;
+
+            /* Everything logged while handling this request is tagged with it */
+            let _span = debug_span!("request", req_resp, address = first.address.to_native(),
+                                    frames = len).entered();
+
+            trace!("Request: req_resp {:x?} over {} frames", req_resp, request.len());
+
+            /* Dispatch request frames to their handlers */
+            let request_type = RequestType::try_from(req_resp)
+                .unwrap_or(RequestType::Unsupported(req_resp));
+            let start = self.profile.as_ref().map(|_| Instant::now());
+
+            let rejected = if ambiguous {
+                self.reject(request_type, request)
+            } else {
+                self.check_reserved(request_type, request)
+            };
+
+            let res: RequestResponse = rejected.unwrap_or_else(|| {
+                match request_type {
+                    RequestType::ProgramKey => {
+                        self.program_key(request)
                     }
-                    VIRTIO_RPMB_REQ_GET_WRITE_COUNTER => {
-                        self.get_write_counter(frame)
+                    RequestType::GetWriteCounter => {
+                        self.get_write_counter(request[0])
                     }
-                    VIRTIO_RPMB_REQ_RESULT_READ => {
-                        match pending {
-                            RequestResponse::PendingResponse{req_resp, result} => {
-                                pending = RequestResponse::NoResponse;
-                                RequestResponse::Response(VirtIORPMBFrame::result(req_resp, result))
+                    RequestType::DataWrite => {
+                        self.data_write(request)
+                    }
+                    RequestType::DataRead => {
+                        self.data_read(request[0], response_bufs.saturating_sub(replies.len()))
+                    }
+                    RequestType::ResultRead => {
+                        match self.pending.write_or_recover().take() {
+                            Some(pending) => {
+                                RequestResponse::Response(VirtIORPMBFrame::pending_result(&pending))
                             }
-                            _ => {
+                            None => {
                                 RequestResponse::NoResponse
                             }
                         }
                     }
-                    _ => {
+                    RequestType::Unsupported(req_resp) => {
                         warn!("Un-handled req_resp {:x?}", req_resp);
-                        RequestResponse::NoResponse
+                        /* Reject it if we can so the guest isn't left waiting */
+                        if replies.len() < buf_lens.len() {
+                            RequestResponse::Response(
+                                VirtIORPMBFrame::result(req_resp, VIRTIO_RPMB_RES_GENERAL_FAILURE,
+                                                        request[0].nonce))
+                        } else {
+                            RequestResponse::NoResponse
+                        }
                     }
-                };
+                }
+            });
 
-                trace!("Result: {:x?}", &res);
+            if let (Some(profile), Some(start)) = (&self.profile, start) {
+                profile.lock_or_recover().record(request_type.name(), start.elapsed());
+            }
 
-                /*
-                 * After we have handled the frame we either have a
-                 * response to send, a deferred status that might be
-                 * queried later or nothing to send at all.
-                 */
+            trace!("Result: {:x?}", &res);
+
+            /*
+             * After we have handled the request we either have a
+             * response to send, a deferred status that might be
+             * queried later or nothing to send at all.
+             */
+            let frames = match res {
+                RequestResponse::Response(frame) => vec![frame],
+                RequestResponse::MultiResponse(frames) => frames,
+                // No immediate response, wait for query
+                RequestResponse::PendingResponse{req_resp, result} => {
+                    /* Writes echo where they went, a key has no address */
+                    let (address, block_count) = match request_type {
+                        RequestType::DataWrite => (first.address.to_native(),
+                                                   first.block_count.to_native()),
+                        _ => (0, 0),
+                    };
+                    *self.pending.write_or_recover() = Some(ResultReqResp {
+                        req_resp,
+                        result,
+                        write_counter: self.backend.get_write_count(),
+                        address,
+                        block_count,
+                    });
+                    Vec::new()
+                }
+                RequestResponse::NoResponse => {
+                    info!("no response needed");
+                    Vec::new()
+                }
+            };
 
-                let replied_bytes = match res {
-                    RequestResponse::Response(frame) => {
+            for frame in frames {
+                /* A guest that wants a reply must give us somewhere to put it */
+                let buf_len = *buf_lens
+                    .get(replies.len())
+                    .ok_or(Error::UnexpectedReadDescriptor)?;
 
-                        // we really should take one
-                        let result_buf = writeable[0];
+                /* Never leave the guest with half a frame */
+                if buf_len as usize != size_of::<VirtIORPMBFrame>() {
+                    error!("Unexpected response buffer size: {}", buf_len);
+                    return Err(Error::UnexpectedDescriptorSize);
+                }
 
-                        desc_chain
-                            .memory()
-                            .write_obj::<VirtIORPMBFrame>(frame, result_buf.addr())
-                            .map_err(|_| Error::DescriptorWriteFailed)?;
+                replies.push(frame);
+            }
+        } // for each request
 
-                        size_of::<VirtIORPMBFrame>() as u32
-                    }
-                    // No immediate response, wait for query
-                    RequestResponse::PendingResponse{req_resp, result} => {
-                        pending = RequestResponse::PendingResponse{req_resp,
-                                                             result};
-                        0
-                    }
-                    _ => {
-                        info!("no response needed");
-                        0
-                    }
-                };
+        Ok(replies)
+    }
 
-                consumed += replied_bytes;
+    /*
+     * Handle a single chain and return it to the used ring. A chain
+     * we can't make sense of is logged and returned unused so one
+     * bad request from the guest doesn't stop the rest of the queue.
+     */
+    pub fn process_chain(&self, queue: &mut RpmbQueue, desc_chain: RpmbDescriptorChain) {
+        let consumed = match self.process_request(&desc_chain) {
+            Ok(consumed) => consumed,
+            Err(e) => {
+                warn!("dropping chain {}: {}", desc_chain.head_index(), e);
+                0
+            }
+        };
 
-            } // for each readable frame
+        if queue.add_used(desc_chain.head_index(), consumed).is_err() {
+            warn!("Couldn't return used consumed descriptors to the ring");
+        }
+    }
 
-            if vring
-                .mut_queue()
-                .add_used(desc_chain.head_index(), consumed)
-                .is_err()
-            {
-                warn!("Couldn't return used consumed descriptors to the ring");
-            }
+    /*
+     * Process the messages in the vring and dispatch replies.
+     *
+     * A bad chain is the guest's problem and process_chain deals
+     * with it without stopping the queue. Only failing to walk the
+     * queue itself, which means the rings are broken, is passed back
+     * to the framework.
+     */
+    fn process_queue(&self, vring: &mut Vring) -> Result<bool> {
+        debug_assert!(self.mem.is_some(), "guest memory not set before processing queue");
+        let _span = debug_span!("process_queue").entered();
 
+        let requests: Vec<_> = vring
+            .mut_queue()
+            .iter()
+            .map_err(|_| Error::DescriptorNotFound)?
+            .collect();
 
-            // Send notification once all the requests are processed
-            vring
-                .signal_used_queue()
-                .map_err(|_| Error::DescriptorSendFailed)?;
+        if requests.is_empty() {
+            return Ok(true);
+        }
+
+        /* Iterate over the requests and handle the messages */
+        for desc_chain in requests {
+            self.process_chain(vring.mut_queue(), desc_chain);
+        }
+
+        /*
+         * One notification covers everything we just used, there's
+         * no point interrupting the guest for every chain in a burst.
+         */
+        if let Err(e) = vring.signal_used_queue() {
+            /* The used ring is updated, the guest will see it next time */
+            warn!("failed to signal used queue: {}", e);
+        }
+
+        debug!("stats: {:?}", self.backend.stats());
+        if let Some(profile) = &self.profile {
+            profile.lock_or_recover().maybe_report();
         }
 
         Ok(true)
@@ -424,45 +1330,91 @@ impl VhostUserRpmb {
  */
 impl VhostUserBackend for VhostUserRpmb {
     fn num_queues(&self) -> usize {
-        NUM_QUEUES
+        self.num_queues
     }
 
     fn max_queue_size(&self) -> usize {
-        QUEUE_SIZE
+        self.queue_size
     }
 
     fn features(&self) -> u64 {
-        /* this set matches the current libvhost defaults except VHOST_F_LOG_ALL*/
+        /*
+         * This set matches the current libvhost defaults except
+         * VHOST_F_LOG_ALL and VIRTIO_F_NOTIFY_ON_EMPTY. The latter is
+         * a legacy only feature a VERSION_1 device shouldn't offer.
+         * We signal after every pass that used buffers and always
+         * drain the queue so the guest is never left waiting anyway.
+         */
         let feat: u64 = 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_F_NOTIFY_ON_EMPTY
             | 1 << VIRTIO_RING_F_INDIRECT_DESC
             | 1 << VIRTIO_RING_F_EVENT_IDX
             | VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits();
+        let feat = feat & !self.disabled_features;
         info!("{:#018x}", &feat);
         info!("{:#018x}", VhostUserVirtioFeatures::PROTOCOL_FEATURES.bits());
         feat
     }
 
+    /*
+     * The frontend acks features every time a driver initialises the
+     * device, so use it as our reset point.
+     */
+    fn acked_features(&mut self, features: u64) {
+        info!("acked features: {:#018x}", features);
+        self.acked_features = features;
+        if let Err(e) = self.reset() {
+            error!("failed to reset device: {}", e);
+        }
+    }
+
+    /*
+     * virtio-rpmb only has a single request queue so we don't
+     * advertise MQ. Nor STATUS, vhost-user-backend has no way to
+     * pass the device status on to us.
+     */
     fn protocol_features(&self) -> VhostUserProtocolFeatures {
         let pfeat: VhostUserProtocolFeatures = VhostUserProtocolFeatures::REPLY_ACK
             | VhostUserProtocolFeatures::CONFIG
-            | VhostUserProtocolFeatures::RESET_DEVICE
-            | VhostUserProtocolFeatures::STATUS
-            | VhostUserProtocolFeatures::MQ;
+            | VhostUserProtocolFeatures::RESET_DEVICE;
         info!("protocol features: {:?}", pfeat);
         pfeat
     }
 
-    fn get_config(&self, _offset: u32, _size: u32) -> Vec<u8> {
-        let config: Vec<u8> = vec![self.backend.get_capacity(), 1, 1];
+    fn get_config(&self, offset: u32, size: u32) -> Vec<u8> {
+        let config = self.config();
         info!("{:?}", &config);
-        config
+
+        let mut bytes = config.as_slice().to_vec();
+        if let Some(serial) = &self.serial {
+            bytes.extend_from_slice(serial);
+        }
+
+        /*
+         * Frontends may read the config in pieces, anything past the
+         * end of our config reads as zero.
+         */
+        let start = (offset as usize).min(bytes.len());
+        let end = (offset as usize).saturating_add(size as usize).min(bytes.len());
+        if end - start < size as usize {
+            warn!("config read {}+{} past end of config", offset, size);
+        }
+
+        let mut window = bytes[start..end].to_vec();
+        window.resize(size as usize, 0);
+        window
     }
 
-    // fn set_config(&mut self, _offset: u32, _buf: &[u8]) -> result::Result<(), io::Error> {
-    //     dbg!("set_config");
-    //     Ok(())
-    // }
+    /*
+     * Every field of the RPMB config describes the device so none of
+     * it is writeable by the guest, including the serial after it.
+     */
+    fn set_config(&mut self, offset: u32, buf: &[u8]) -> VhostUserBackendResult<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        warn!("guest tried to write {} bytes of config at {}", buf.len(), offset);
+        Err(Error::ConfigReadOnly.into())
+    }
 
     fn set_event_idx(&mut self, enabled: bool) {
         dbg!(self.event_idx = enabled);
@@ -470,11 +1422,16 @@ impl VhostUserBackend for VhostUserRpmb {
 
     fn update_memory(
         &mut self,
-        _mem: GuestMemoryAtomic<GuestMemoryMmap>,
+        mem: GuestMemoryAtomic<GuestMemoryMmap>,
     ) -> VhostUserBackendResult<()> {
+        self.mem = Some(mem);
         Ok(())
     }
 
+    fn exit_event(&self, _thread_index: usize) -> Option<(EventFd, Option<u16>)> {
+        Some((self.exit_event.try_clone().expect("Cloning exit eventfd"), Some(self.kill_event())))
+    }
+
     fn handle_event(
         &self,
         device_event: u16,
@@ -490,8 +1447,8 @@ impl VhostUserBackend for VhostUserRpmb {
         }
 
         match device_event {
-            0 => {
-                let mut vring = vrings[0].write().unwrap();
+            queue if (queue as usize) < self.num_queues => {
+                let mut vring = vrings[queue as usize].write_or_recover();
 
                 if self.event_idx {
                     // vm-virtio's Queue implementation only checks avail_index
@@ -499,10 +1456,12 @@ impl VhostUserBackend for VhostUserRpmb {
                     // calling process_queue() until it stops finding new
                     // requests on the queue.
                     loop {
-                        vring.mut_queue().disable_notification().unwrap();
+                        vring.mut_queue().disable_notification()
+                            .map_err(|_| Error::NotificationFailed)?;
 
                         self.process_queue(&mut vring)?;
-                        if !vring.mut_queue().enable_notification().unwrap() {
+                        if !vring.mut_queue().enable_notification()
+                            .map_err(|_| Error::NotificationFailed)? {
                             break;
                         }
                     }
@@ -511,6 +1470,10 @@ impl VhostUserBackend for VhostUserRpmb {
                     self.process_queue(&mut vring)?;
                 }
             }
+            event if event == self.kill_event() => {
+                info!("exit event received, stopping");
+                return Ok(true);
+            }
             _ => {
                 warn!("unhandled device_event: {}", device_event);
                 return Err(Error::HandleEventUnknownEvent.into());
@@ -519,3 +1482,29 @@ impl VhostUserBackend for VhostUserRpmb {
         Ok(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_type_codes() {
+        let known = [
+            (VIRTIO_RPMB_REQ_PROGRAM_KEY, RequestType::ProgramKey),
+            (VIRTIO_RPMB_REQ_GET_WRITE_COUNTER, RequestType::GetWriteCounter),
+            (VIRTIO_RPMB_REQ_DATA_WRITE, RequestType::DataWrite),
+            (VIRTIO_RPMB_REQ_DATA_READ, RequestType::DataRead),
+            (VIRTIO_RPMB_REQ_RESULT_READ, RequestType::ResultRead),
+        ];
+        for (code, request_type) in known.iter() {
+            assert_eq!(RequestType::try_from(*code), Ok(*request_type));
+            assert_eq!(request_type.code(), *code);
+        }
+
+        /* Neither a response code nor anything else is a request */
+        for code in [0x0000, 0x0006, VIRTIO_RPMB_RESP_DATA_READ, 0xffff].iter() {
+            assert_eq!(RequestType::try_from(*code), Err(*code));
+            assert_eq!(RequestType::Unsupported(*code).code(), *code);
+        }
+    }
+}
@@ -0,0 +1,28 @@
+/*
+ * Tests of the command line of the daemon itself
+ */
+
+use std::process::Command;
+
+use vmm_sys_util::tempdir::TempDir;
+
+#[test]
+fn test_old_key_options() {
+    let dir = TempDir::new().unwrap();
+    let image = dir.as_path().join("flash.img");
+    let key = dir.as_path().join("key");
+    std::fs::write(&image, vec![0; 128 * 1024]).unwrap();
+    std::fs::write(&key, [0x42; 32]).unwrap();
+
+    /* Command lines from before --key-file still work */
+    let output = Command::new(env!("CARGO_BIN_EXE_vhost-user-rpmb"))
+        .arg("--check")
+        .arg("--flash-path").arg(&image)
+        .arg("--key-path").arg(&key)
+        .arg("--key-set")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "check failed: {:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("key: programmed"), "{}", stdout);
+}
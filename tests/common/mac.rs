@@ -0,0 +1,36 @@
+/*
+ * Reference RPMB MAC calculation
+ *
+ * This deliberately mirrors the Linux kernel's layout rather than
+ * reusing the device code: the HMAC-SHA256 covers the 284 bytes of
+ * each frame from the start of data through to the end of req_resp.
+ */
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use super::{Frame, DATA, FRAME_SIZE};
+
+/// Size of the region of each frame covered by the MAC
+pub const MAC_REGION_SIZE: usize = 284;
+
+/*
+ * The MAC over a sequence of frames.
+ */
+pub fn mac(key: &[u8], frames: &[Frame]) -> [u8; 32] {
+    assert_eq!(FRAME_SIZE - DATA, MAC_REGION_SIZE);
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    for frame in frames {
+        mac.update(&frame.to_bytes()[DATA..]);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/*
+ * Sign a sequence of request frames, the MAC goes in the last one.
+ */
+pub fn sign(key: &[u8], frames: &mut [Frame]) {
+    let mac = mac(key, frames);
+    frames.last_mut().unwrap().key_mac = mac;
+}
@@ -0,0 +1,361 @@
+/*
+ * Test harness for driving a VhostUserRpmb device over a real
+ * virtqueue laid out in guest memory.
+ */
+
+#![allow(dead_code)]
+
+pub mod mac;
+
+use std::convert::TryInto;
+
+use vm_memory::{Address, Bytes, GuestAddress, GuestAddressSpace, GuestMemoryAtomic, GuestMemoryMmap};
+use vm_virtio::Queue;
+use vmm_sys_util::tempfile::TempFile;
+
+use vhost_user_rpmb::rpmb::RpmbBackend;
+use vhost_user_rpmb::vhu_rpmb::{Error, VhostUserRpmb, VIRTIO_RPMB_REQ_DATA_WRITE,
+                                 VIRTIO_RPMB_REQ_PROGRAM_KEY, VIRTIO_RPMB_REQ_RESULT_READ};
+
+pub const FRAME_SIZE: usize = 512;
+pub const KEY: [u8; 32] = [0x42; 32];
+
+const QUEUE_SIZE: u16 = 64;
+const MEM_SIZE: usize = 0x10_0000;
+const DESC_TABLE: u64 = 0x1000;
+const AVAIL_RING: u64 = 0x2000;
+const USED_RING: u64 = 0x3000;
+const BUFFERS: u64 = 0x1_0000;
+
+/* Response buffers start out filled with this */
+const POISON: u8 = 0xff;
+
+const VIRTQ_DESC_F_NEXT: u16 = 0x1;
+const VIRTQ_DESC_F_WRITE: u16 = 0x2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 0x4;
+
+pub use mac::{mac, sign};
+
+// Offsets of the fields in the 512 byte frame
+const KEY_MAC: usize = 196;
+pub const DATA: usize = KEY_MAC + 32;
+const NONCE: usize = DATA + 256;
+const WRITE_COUNTER: usize = NONCE + 16;
+const ADDRESS: usize = WRITE_COUNTER + 4;
+const BLOCK_COUNT: usize = ADDRESS + 2;
+const RESULT: usize = BLOCK_COUNT + 2;
+const REQ_RESP: usize = RESULT + 2;
+
+/*
+ * Our own encoding of a virtio-rpmb frame, kept independent of the
+ * device's so the tests check the wire format.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub key_mac: [u8; 32],
+    pub data: [u8; 256],
+    pub nonce: [u8; 16],
+    pub write_counter: u32,
+    pub address: u16,
+    pub block_count: u16,
+    pub result: u16,
+    pub req_resp: u16,
+}
+
+impl Frame {
+    pub fn new(req_resp: u16) -> Self {
+        Frame {
+            key_mac: [0; 32],
+            data: [0; 256],
+            nonce: [0; 16],
+            write_counter: 0,
+            address: 0,
+            block_count: 0,
+            result: 0,
+            req_resp,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; FRAME_SIZE] {
+        let mut buf = [0; FRAME_SIZE];
+        buf[KEY_MAC..DATA].copy_from_slice(&self.key_mac);
+        buf[DATA..NONCE].copy_from_slice(&self.data);
+        buf[NONCE..WRITE_COUNTER].copy_from_slice(&self.nonce);
+        buf[WRITE_COUNTER..ADDRESS].copy_from_slice(&self.write_counter.to_be_bytes());
+        buf[ADDRESS..BLOCK_COUNT].copy_from_slice(&self.address.to_be_bytes());
+        buf[BLOCK_COUNT..RESULT].copy_from_slice(&self.block_count.to_be_bytes());
+        buf[RESULT..REQ_RESP].copy_from_slice(&self.result.to_be_bytes());
+        buf[REQ_RESP..].copy_from_slice(&self.req_resp.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8; FRAME_SIZE]) -> Self {
+        Frame {
+            key_mac: buf[KEY_MAC..DATA].try_into().unwrap(),
+            data: buf[DATA..NONCE].try_into().unwrap(),
+            nonce: buf[NONCE..WRITE_COUNTER].try_into().unwrap(),
+            write_counter: u32::from_be_bytes(buf[WRITE_COUNTER..ADDRESS].try_into().unwrap()),
+            address: u16::from_be_bytes(buf[ADDRESS..BLOCK_COUNT].try_into().unwrap()),
+            block_count: u16::from_be_bytes(buf[BLOCK_COUNT..RESULT].try_into().unwrap()),
+            result: u16::from_be_bytes(buf[RESULT..REQ_RESP].try_into().unwrap()),
+            req_resp: u16::from_be_bytes(buf[REQ_RESP..].try_into().unwrap()),
+        }
+    }
+}
+
+/*
+ * A 128KB device, held in memory unless it was created with an
+ * image file.
+ */
+pub struct TestDevice {
+    pub image: Option<TempFile>,
+    pub rpmb: VhostUserRpmb,
+}
+
+impl TestDevice {
+    pub fn new() -> Self {
+        TestDevice {
+            image: None,
+            rpmb: VhostUserRpmb::new(RpmbBackend::in_memory(1)).unwrap(),
+        }
+    }
+
+    pub fn with_image() -> Self {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(128 * 1024).unwrap();
+        let backend = RpmbBackend::new(image.as_path()).unwrap();
+        TestDevice {
+            image: Some(image),
+            rpmb: VhostUserRpmb::new(backend).unwrap(),
+        }
+    }
+}
+
+/*
+ * A split virtqueue in guest memory that we fill from the driver
+ * side before handing the chains to the device.
+ */
+pub struct TestQueue {
+    pub mem: GuestMemoryAtomic<GuestMemoryMmap>,
+    queue: Queue<GuestMemoryAtomic<GuestMemoryMmap>>,
+    next_desc: u16,
+    next_avail: u16,
+    next_buf: u64,
+}
+
+impl TestQueue {
+    pub fn new() -> Self {
+        let mem = GuestMemoryAtomic::new(
+            GuestMemoryMmap::from_ranges(&[(GuestAddress(0), MEM_SIZE)]).unwrap());
+        let mut queue = Queue::new(mem.clone(), QUEUE_SIZE);
+        queue.size = QUEUE_SIZE;
+        queue.ready = true;
+        queue.desc_table = GuestAddress(DESC_TABLE);
+        queue.avail_ring = GuestAddress(AVAIL_RING);
+        queue.used_ring = GuestAddress(USED_RING);
+
+        TestQueue {
+            mem,
+            queue,
+            next_desc: 0,
+            next_avail: 0,
+            next_buf: BUFFERS,
+        }
+    }
+
+    fn alloc(&mut self, len: usize) -> GuestAddress {
+        let addr = GuestAddress(self.next_buf);
+        self.next_buf += len as u64;
+        addr
+    }
+
+    /*
+     * Add a descriptor chain made of raw (address, len, writeable)
+     * buffers and publish it in the available ring.
+     */
+    pub fn add_raw_chain(&mut self, bufs: &[(GuestAddress, u32, bool)]) {
+        let head = self.next_desc;
+        self.write_descs(GuestAddress(DESC_TABLE + head as u64 * 16), head, bufs);
+        self.next_desc += bufs.len() as u16;
+        self.publish(head);
+    }
+
+    /*
+     * Make the chain starting at head available to the device.
+     */
+    fn publish(&mut self, head: u16) {
+        let mem = self.mem.memory();
+        let slot = self.next_avail % QUEUE_SIZE;
+        mem.write_obj(head, GuestAddress(AVAIL_RING + 4 + slot as u64 * 2)).unwrap();
+        self.next_avail += 1;
+        mem.write_obj(self.next_avail, GuestAddress(AVAIL_RING + 2)).unwrap();
+    }
+
+    /*
+     * Write descriptors for bufs starting at desc_addr, where the
+     * first of them has index first in its table.
+     */
+    fn write_descs(&self, desc_addr: GuestAddress, first: u16, bufs: &[(GuestAddress, u32, bool)]) {
+        let mem = self.mem.memory();
+
+        for (i, (addr, len, writeable)) in bufs.iter().enumerate() {
+            let mut flags = if *writeable { VIRTQ_DESC_F_WRITE } else { 0 };
+            if i + 1 < bufs.len() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+
+            let desc = desc_addr.unchecked_add(i as u64 * 16);
+            mem.write_obj(addr.0, desc).unwrap();
+            mem.write_obj(*len, desc.unchecked_add(8)).unwrap();
+            mem.write_obj(flags, desc.unchecked_add(12)).unwrap();
+            mem.write_obj(first + i as u16 + 1, desc.unchecked_add(14)).unwrap();
+        }
+    }
+
+    /*
+     * Like add_chain but the buffers are described by an indirect
+     * table referenced from a single descriptor in the ring.
+     */
+    pub fn add_indirect_chain(&mut self, requests: &[Frame], responses: usize) -> Vec<GuestAddress> {
+        let (bufs, response_addrs) = self.alloc_frames(requests, responses);
+
+        let table = self.alloc(bufs.len() * 16);
+        self.write_descs(table, 0, &bufs);
+
+        let mem = self.mem.memory();
+        let head = self.next_desc;
+        let desc = GuestAddress(DESC_TABLE + head as u64 * 16);
+        mem.write_obj(table.0, desc).unwrap();
+        mem.write_obj(bufs.len() as u32 * 16, desc.unchecked_add(8)).unwrap();
+        mem.write_obj(VIRTQ_DESC_F_INDIRECT, desc.unchecked_add(12)).unwrap();
+        mem.write_obj(0u16, desc.unchecked_add(14)).unwrap();
+        self.next_desc += 1;
+
+        self.publish(head);
+        response_addrs
+    }
+
+    /*
+     * Copy the request frames into guest memory and allocate space
+     * for the responses, returning the buffers for a chain and the
+     * response addresses.
+     */
+    fn alloc_frames(&mut self, requests: &[Frame], responses: usize)
+                    -> (Vec<(GuestAddress, u32, bool)>, Vec<GuestAddress>) {
+        let mut bufs = Vec::new();
+
+        for frame in requests {
+            let addr = self.alloc(FRAME_SIZE);
+            self.mem.memory().write_slice(&frame.to_bytes(), addr).unwrap();
+            bufs.push((addr, FRAME_SIZE as u32, false));
+        }
+
+        let response_addrs: Vec<_> = (0..responses).map(|_| self.alloc(FRAME_SIZE)).collect();
+        for addr in &response_addrs {
+            self.mem.memory().write_slice(&[POISON; FRAME_SIZE], *addr).unwrap();
+            bufs.push((*addr, FRAME_SIZE as u32, true));
+        }
+
+        (bufs, response_addrs)
+    }
+
+    /*
+     * Add a chain of request frames followed by a number of response
+     * buffers, returning the addresses of the response buffers.
+     */
+    pub fn add_chain(&mut self, requests: &[Frame], responses: usize) -> Vec<GuestAddress> {
+        let (bufs, response_addrs) = self.alloc_frames(requests, responses);
+        self.add_raw_chain(&bufs);
+        response_addrs
+    }
+
+    /*
+     * Hand every available chain to the device, returning what it
+     * reported for each (the used length on success).
+     */
+    pub fn process(&mut self, rpmb: &VhostUserRpmb) -> Vec<Result<u32, Error>> {
+        let chains: Vec<_> = self.queue.iter().unwrap().collect();
+        chains.iter().map(|chain| rpmb.process_request(chain)).collect()
+    }
+
+    /*
+     * Hand every available chain to the device the way the queue
+     * handler does, returning the used ring entries as (head, len).
+     */
+    pub fn process_chains(&mut self, rpmb: &VhostUserRpmb) -> Vec<(u32, u32)> {
+        let chains: Vec<_> = self.queue.iter().unwrap().collect();
+        for chain in chains {
+            rpmb.process_chain(&mut self.queue, chain);
+        }
+        self.used()
+    }
+
+    pub fn used(&self) -> Vec<(u32, u32)> {
+        let mem = self.mem.memory();
+        let idx: u16 = mem.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        (0..idx)
+            .map(|i| {
+                let elem = GuestAddress(USED_RING + 4 + (i % QUEUE_SIZE) as u64 * 8);
+                (mem.read_obj(elem).unwrap(), mem.read_obj(elem.unchecked_add(4)).unwrap())
+            })
+            .collect()
+    }
+
+    /*
+     * Has the device written anything to the response buffer at addr.
+     * No real response is all POISON, if nothing else req_resp isn't.
+     */
+    pub fn written(&self, addr: GuestAddress) -> bool {
+        let mut buf = [0; FRAME_SIZE];
+        self.mem.memory().read_slice(&mut buf, addr).unwrap();
+        buf.iter().any(|b| *b != POISON)
+    }
+
+    pub fn read_frame(&self, addr: GuestAddress) -> Frame {
+        let mut buf = [0; FRAME_SIZE];
+        self.mem.memory().read_slice(&mut buf, addr).unwrap();
+        Frame::from_bytes(&buf)
+    }
+}
+
+/*
+ * Run a single chain through the device, returning the used length
+ * and the frames written to the response buffers.
+ */
+pub fn request(dev: &TestDevice, requests: &[Frame], responses: usize) -> (u32, Vec<Frame>) {
+    let mut queue = TestQueue::new();
+    let addrs = queue.add_chain(requests, responses);
+    let used = queue.process(&dev.rpmb).pop().unwrap().unwrap();
+
+    /* The used length must match what actually landed in the buffers */
+    let written = addrs.iter().filter(|a| queue.written(**a)).count();
+    assert_eq!(used as usize, written * FRAME_SIZE, "used length doesn't match bytes written");
+
+    (used, addrs.into_iter().map(|a| queue.read_frame(a)).collect())
+}
+
+/*
+ * Program KEY into the device, returning the result frame.
+ */
+pub fn program_key(dev: &TestDevice) -> Frame {
+    let mut program = Frame::new(VIRTIO_RPMB_REQ_PROGRAM_KEY);
+    program.key_mac = KEY;
+    program.block_count = 1;
+    let (_, resp) = request(dev, &[program, Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    resp[0].clone()
+}
+
+/*
+ * Write a single signed block, returning the result frame.
+ */
+pub fn write_block(dev: &TestDevice, address: u16, counter: u32, data: [u8; 256]) -> Frame {
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.address = address;
+    write.block_count = 1;
+    write.write_counter = counter;
+    write.data = data;
+    let mut frames = [write];
+    sign(&KEY, &mut frames);
+    let (_, resp) = request(dev, &[frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    resp[0].clone()
+}
@@ -0,0 +1,117 @@
+/*
+ * Tests of requests racing each other from several threads
+ */
+
+mod common;
+
+use std::io::Result;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use common::*;
+use vhost_user_rpmb::rpmb::RpmbBackend;
+use vhost_user_rpmb::store::{MemStore, RpmbStore};
+use vhost_user_rpmb::vhu_rpmb::*;
+
+const WRITES: u32 = 32;
+
+fn read_counter(dev: &TestDevice) -> u32 {
+    let (_, resp) = request(dev, &[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    resp[0].write_counter
+}
+
+#[test]
+fn test_counter_with_racing_writers() {
+    let dev = Arc::new(TestDevice::new());
+    program_key(&dev);
+
+    /*
+     * Both writers try every counter value, each value must be
+     * accepted exactly once no matter how they interleave.
+     */
+    let writers: Vec<_> = (0..2u8)
+        .map(|n| {
+            let dev = dev.clone();
+            thread::spawn(move || {
+                for counter in 0..WRITES {
+                    write_block(&dev, counter as u16, counter, [n; 256]);
+                }
+            })
+        })
+        .collect();
+
+    /* Meanwhile the counter must only ever move forward */
+    let reader = {
+        let dev = dev.clone();
+        thread::spawn(move || {
+            let mut last = 0;
+            while last < WRITES {
+                let counter = read_counter(&dev);
+                assert!(counter >= last, "counter went backwards {} -> {}", last, counter);
+                last = counter;
+            }
+        })
+    };
+
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    reader.join().unwrap();
+
+    assert_eq!(read_counter(&dev), WRITES);
+}
+
+/*
+ * A store whose first write panics, part way through an
+ * authenticated write with the write lock held.
+ */
+#[derive(Debug)]
+struct PanickingStore {
+    inner: MemStore,
+    panicked: AtomicBool,
+}
+
+impl RpmbStore for PanickingStore {
+    fn capacity(&self) -> u8 {
+        self.inner.capacity()
+    }
+
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    fn read_blocks(&self, address: u16, count: u16) -> Result<Vec<u8>> {
+        self.inner.read_blocks(address, count)
+    }
+
+    fn write_blocks(&self, address: u16, data: &[u8]) -> Result<()> {
+        if !self.panicked.swap(true, Ordering::SeqCst) {
+            panic!("store failure");
+        }
+        self.inner.write_blocks(address, data)
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_survives_poisoned_lock() {
+    let store = PanickingStore { inner: MemStore::new(1), panicked: AtomicBool::new(false) };
+    let dev = TestDevice {
+        image: None,
+        rpmb: VhostUserRpmb::new(RpmbBackend::with_store(Box::new(store))).unwrap(),
+    };
+    program_key(&dev);
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| write_block(&dev, 0, 0, [0x11; 256])));
+    assert!(result.is_err());
+
+    /* The write lock was poisoned but writes carry on */
+    assert_eq!(write_block(&dev, 0, 0, [0x22; 256]).result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(read_counter(&dev), 1);
+}
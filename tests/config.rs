@@ -0,0 +1,124 @@
+/*
+ * Tests of the device config space
+ */
+
+mod common;
+
+use common::*;
+use vhost::vhost_user::message::VhostUserProtocolFeatures;
+use vhost_user_backend::VhostUserBackend;
+
+use vhost_user_rpmb::rpmb::RpmbBackend;
+use vhost_user_rpmb::vhu_rpmb::{VhostUserRpmb, NUM_QUEUES, QUEUE_SIZE};
+
+#[test]
+fn test_full_config() {
+    let dev = TestDevice::new();
+    /* 128KB image, one block writes, reads limited by the queue */
+    assert_eq!(dev.rpmb.get_config(0, 3), vec![1, 1, 255]);
+}
+
+#[test]
+fn test_partial_config() {
+    let dev = TestDevice::new();
+    /* just max_wr_cnt */
+    assert_eq!(dev.rpmb.get_config(1, 1), vec![1]);
+}
+
+#[test]
+fn test_config_past_end() {
+    let dev = TestDevice::new();
+    assert_eq!(dev.rpmb.get_config(2, 4), vec![255, 0, 0, 0]);
+    assert_eq!(dev.rpmb.get_config(8, 2), vec![0, 0]);
+}
+
+#[test]
+fn test_config_tracks_resize() {
+    let dev = TestDevice::with_image();
+    assert_eq!(dev.rpmb.config().capacity, 1);
+
+    dev.image.as_ref().unwrap().as_file().set_len(2 * 128 * 1024).unwrap();
+    assert_eq!(dev.rpmb.config().capacity, 2);
+    assert_eq!(dev.rpmb.get_config(0, 1), vec![2]);
+}
+
+#[test]
+fn test_serial() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_serial("rpmb0");
+    assert_eq!(dev.rpmb.get_config(3, 6), b"rpmb0\0".to_vec());
+
+    /* Long serials are cut to fit, then zero past the end */
+    dev.rpmb.set_serial("0123456789abcdefghijklmnop");
+    assert_eq!(dev.rpmb.get_config(3, 21), b"0123456789abcdefghij\0".to_vec());
+}
+
+#[test]
+fn test_features() {
+    const NOTIFY_ON_EMPTY: u64 = 1 << 24;
+    const EVENT_IDX: u64 = 1 << 29;
+    const VERSION_1: u64 = 1 << 32;
+
+    let mut dev = TestDevice::new();
+    let features = dev.rpmb.features();
+    assert_eq!(features & VERSION_1, VERSION_1);
+    assert_eq!(features & EVENT_IDX, EVENT_IDX);
+    /* Legacy only, not for a VERSION_1 device */
+    assert_eq!(features & NOTIFY_ON_EMPTY, 0);
+
+    dev.rpmb.disable_event_idx();
+    assert_eq!(dev.rpmb.features() & EVENT_IDX, 0);
+}
+
+#[test]
+fn test_config_read_only() {
+    let mut dev = TestDevice::new();
+    assert!(dev.rpmb.set_config(0, &[4]).is_err());
+    assert!(dev.rpmb.set_config(3, b"serial").is_err());
+    assert!(dev.rpmb.set_config(0, &[]).is_ok());
+
+    /* and nothing changed */
+    assert_eq!(dev.rpmb.get_config(0, 3), vec![1, 1, 255]);
+}
+
+#[test]
+fn test_queue_geometry() {
+    let dev = TestDevice::new();
+    assert_eq!(dev.rpmb.num_queues(), NUM_QUEUES);
+    assert_eq!(dev.rpmb.max_queue_size(), QUEUE_SIZE);
+
+    let rpmb = VhostUserRpmb::with_queues(RpmbBackend::in_memory(1), 2, 256).unwrap();
+    assert_eq!(rpmb.num_queues(), 2);
+    assert_eq!(rpmb.max_queue_size(), 256);
+
+    assert!(VhostUserRpmb::with_queues(RpmbBackend::in_memory(1), 0, 256).is_err());
+    assert!(VhostUserRpmb::with_queues(RpmbBackend::in_memory(1), 1, 100).is_err());
+
+    /* Too small for even one request and its response */
+    assert!(VhostUserRpmb::with_queues(RpmbBackend::in_memory(1), 1, 1).is_err());
+    let mut dev = TestDevice::new();
+    assert!(dev.rpmb.set_queue_size(1).is_err());
+    assert!(dev.rpmb.set_queue_size(2).is_ok());
+    assert_eq!(dev.rpmb.config().max_rd_cnt, 1);
+}
+
+#[test]
+fn test_state_report() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_serial("rpmb0");
+    dev.rpmb.acked_features(1 << 32);
+
+    let report = dev.rpmb.state_report();
+    assert!(report.contains("acked 0x0000000100000000"));
+    assert!(report.contains("config [01 01 ff 72 70 6d 62 30 00"));
+    assert!(report.contains("writes: 0"));
+}
+
+#[test]
+fn test_protocol_features() {
+    let dev = TestDevice::new();
+    let features = dev.rpmb.protocol_features();
+    assert!(features.contains(VhostUserProtocolFeatures::CONFIG));
+    /* vhost-user-backend can't pass the status on to us */
+    assert!(!features.contains(VhostUserProtocolFeatures::STATUS));
+}
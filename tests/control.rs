@@ -0,0 +1,40 @@
+/*
+ * Tests of the control socket
+ */
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use vmm_sys_util::tempdir::TempDir;
+
+use vhost_user_rpmb::control::{ping, serve, Health};
+
+#[test]
+fn test_ping() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("control.sock");
+    let timeout = Duration::from_secs(5);
+
+    /* Nobody home yet */
+    assert!(ping(&path, timeout).is_err());
+
+    let health = Health { key_programmed: true, write_counter: 42 };
+    let reported = health.clone();
+    serve(&path, move || reported.clone()).unwrap();
+    assert_eq!(ping(&path, timeout).unwrap(), health);
+
+    /* Anything else is answered with an error */
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream.write_all(b"reboot\n").unwrap();
+    let mut reply = String::new();
+    BufReader::new(&stream).read_line(&mut reply).unwrap();
+    assert!(reply.contains("\"error\""));
+
+    /* A stale socket is replaced, anything else isn't */
+    serve(&path, move || health.clone()).unwrap();
+    let file = dir.as_path().join("file");
+    std::fs::write(&file, b"precious").unwrap();
+    assert!(serve(&file, || Health { key_programmed: false, write_counter: 0 }).is_err());
+    assert_eq!(std::fs::read(&file).unwrap(), b"precious");
+}
@@ -0,0 +1,326 @@
+/*
+ * Tests of creating and opening flash images
+ */
+
+use std::os::unix::fs::{FileExt, MetadataExt};
+
+use vmm_sys_util::tempdir::TempDir;
+use vmm_sys_util::tempfile::TempFile;
+
+use vhost_user_rpmb::rpmb::RpmbBackend;
+
+const SIZE: u64 = 256 * 1024;
+
+#[test]
+fn test_create_preallocated() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("flash.img");
+
+    let rpmb = RpmbBackend::builder(&path)
+        .create_size(SIZE)
+        .preallocate(true)
+        .build()
+        .unwrap();
+    assert_eq!(rpmb.get_capacity(), 2);
+
+    /* No holes, every byte is backed by 512 byte disk blocks */
+    let metadata = path.metadata().unwrap();
+    assert_eq!(metadata.len(), SIZE);
+    assert!(metadata.blocks() * 512 >= SIZE);
+    assert_eq!(rpmb.read_blocks(0, 4).unwrap(), vec![0; 1024]);
+}
+
+#[test]
+fn test_window() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(1024 * 1024).unwrap();
+    image.as_file().write_all_at(&[0xaa; 256], 512 * 1024 + 256).unwrap();
+
+    let rpmb = RpmbBackend::builder(image.as_path())
+        .window(512 * 1024, SIZE)
+        .build()
+        .unwrap();
+    assert_eq!(rpmb.get_capacity(), 2);
+    assert_eq!(rpmb.read_blocks(1, 1).unwrap(), vec![0xaa; 256]);
+
+    /* The window has to fit inside the file */
+    assert!(RpmbBackend::builder(image.as_path())
+            .window(1024 * 1024 - 128 * 1024, SIZE)
+            .build()
+            .is_err());
+}
+
+#[test]
+fn test_counter_survives_torn_update() {
+    let dir = TempDir::new().unwrap();
+    let image = dir.as_path().join("flash.img");
+    let counter = dir.as_path().join("counter");
+    let open = || RpmbBackend::builder(&image)
+        .create_size(SIZE)
+        .counter_file(&counter)
+        .build()
+        .unwrap();
+
+    let rpmb = open();
+    rpmb.set_write_count(7);
+    rpmb.persist_counter().unwrap();
+    drop(rpmb);
+
+    /* Crash half way through the next update */
+    let temp = dir.as_path().join("counter.tmp");
+    std::fs::write(&temp, [8, 0]).unwrap();
+
+    let rpmb = open();
+    assert_eq!(rpmb.get_write_count(), 7);
+
+    /* The next update replaces the leftover */
+    rpmb.increment_write_count();
+    rpmb.persist_counter().unwrap();
+    assert!(!temp.exists());
+    assert_eq!(std::fs::read(&counter).unwrap(), 8u32.to_le_bytes());
+}
+
+#[test]
+fn test_initial_counter_only_moves_forward() {
+    let dir = TempDir::new().unwrap();
+    let image = dir.as_path().join("flash.img");
+    let counter = dir.as_path().join("counter");
+    std::fs::write(&counter, 5u32.to_le_bytes()).unwrap();
+    let open = |initial| RpmbBackend::builder(&image)
+        .create_size(SIZE)
+        .counter_file(&counter)
+        .initial_counter(initial)
+        .build();
+
+    /* Going back would let old writes be replayed */
+    assert!(open(4).is_err());
+    assert_eq!(std::fs::read(&counter).unwrap(), 5u32.to_le_bytes());
+
+    let rpmb = open(9).unwrap();
+    assert_eq!(rpmb.get_write_count(), 9);
+    assert_eq!(std::fs::read(&counter).unwrap(), 9u32.to_le_bytes());
+}
+
+#[test]
+fn test_capacity_override() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+
+    let rpmb = RpmbBackend::builder(image.as_path())
+        .capacity(1)
+        .build()
+        .unwrap();
+    assert_eq!(rpmb.get_capacity(), 1);
+    assert_eq!(rpmb.refresh_capacity().unwrap(), 1);
+
+    /* The second 128KB of the file is out of bounds */
+    assert!(rpmb.read_blocks(511, 1).is_ok());
+    assert!(rpmb.read_blocks(512, 1).is_err());
+    assert!(rpmb.write_blocks(512, &[0; 256]).is_err());
+
+    /* but we can't advertise more than the file holds */
+    assert!(RpmbBackend::builder(image.as_path()).capacity(3).build().is_err());
+    assert!(RpmbBackend::builder(image.as_path()).capacity(0).build().is_err());
+}
+
+#[test]
+fn test_deferred_sync() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+
+    let rpmb = RpmbBackend::builder(image.as_path())
+        .sync_on_write(false)
+        .build()
+        .unwrap();
+    rpmb.write_blocks(2, &[0x77; 256]).unwrap();
+    rpmb.flush().unwrap();
+    /* Nothing dirty, nothing to do */
+    rpmb.flush().unwrap();
+
+    let contents = std::fs::read(image.as_path()).unwrap();
+    assert_eq!(&contents[512..768], &[0x77; 256][..]);
+}
+
+#[test]
+fn test_erase() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+    image.as_file().write_all_at(&[0x5a; 4096], 64 * 1024).unwrap();
+
+    let rpmb = RpmbBackend::new(image.as_path()).unwrap();
+    rpmb.program_key([0x42; 32].iter().copied().collect()).unwrap();
+    rpmb.set_write_count(9);
+
+    rpmb.erase().unwrap();
+    assert_eq!(rpmb.read_blocks(0, 1024).unwrap(), vec![0; SIZE as usize]);
+    assert!(rpmb.has_key());
+    assert_eq!(rpmb.get_write_count(), 9);
+}
+
+#[test]
+fn test_geometry() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+
+    let rpmb = RpmbBackend::new(image.as_path()).unwrap();
+    assert_eq!(rpmb.get_capacity(), 2);
+    assert_eq!(rpmb.capacity_bytes(), SIZE);
+    assert_eq!(rpmb.block_size(), 256);
+    assert_eq!(rpmb.block_count(), 1024);
+}
+
+#[test]
+fn test_mirror() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("flash.img");
+    let mirror = dir.as_path().join("mirror.img");
+    std::fs::write(&path, vec![0xaa; SIZE as usize]).unwrap();
+
+    let rpmb = RpmbBackend::builder(&path)
+        .mirror(&mirror)
+        .mirror_strict(true)
+        .mirror_verify(true)
+        .build()
+        .unwrap();
+
+    /* The new mirror starts as a copy */
+    assert_eq!(std::fs::read(&mirror).unwrap(), vec![0xaa; SIZE as usize]);
+
+    rpmb.write_blocks(5, &[0x33; 512]).unwrap();
+    rpmb.flush().unwrap();
+    assert_eq!(rpmb.read_blocks(5, 2).unwrap(), vec![0x33; 512]);
+    assert_eq!(std::fs::read(&mirror).unwrap(), std::fs::read(&path).unwrap());
+    drop(rpmb);
+
+    /* A mirror of the wrong size is refused */
+    std::fs::write(&mirror, vec![0; 128 * 1024]).unwrap();
+    assert!(RpmbBackend::builder(&path).mirror(&mirror).build().is_err());
+}
+
+#[test]
+fn test_unaligned_image() {
+    for len in [0, 64 * 1024, SIZE + 512].iter() {
+        let image = TempFile::new().unwrap();
+        image.as_file().set_len(*len).unwrap();
+        let err = RpmbBackend::new(image.as_path()).err().expect("unaligned image opened");
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("whole number of 128KB units"), "{}", err);
+    }
+}
+
+#[test]
+fn test_truncated_image() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+
+    let rpmb = RpmbBackend::new(image.as_path()).unwrap();
+    assert!(rpmb.read_blocks(600, 1).is_ok());
+
+    /* Some other process cuts the image in half, we fail rather than fault */
+    image.as_file().set_len(SIZE / 2).unwrap();
+    assert!(rpmb.read_blocks(600, 1).is_err());
+    assert!(rpmb.write_blocks(0, &[0; 256]).is_err());
+    assert!(rpmb.flush().is_err());
+}
+
+#[test]
+fn test_direct_io() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("flash.img");
+    std::fs::write(&path, vec![0xaa; SIZE as usize]).unwrap();
+
+    let rpmb = match RpmbBackend::builder(&path).direct_io(true).build() {
+        Ok(rpmb) => rpmb,
+        /* tmpfs and friends can't do O_DIRECT, nothing to test */
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => return,
+        Err(e) => panic!("can't open image: {}", e),
+    };
+    assert_eq!(rpmb.get_capacity(), 2);
+
+    /* Blocks smaller than a sector, the rest of it is untouched */
+    rpmb.write_blocks(3, &[0x33; 512]).unwrap();
+    assert_eq!(rpmb.read_blocks(2, 4).unwrap(),
+               [[0xaa; 256], [0x33; 256], [0x33; 256], [0xaa; 256]].concat());
+    let contents = std::fs::read(&path).unwrap();
+    assert_eq!(&contents[768..1280], &[0x33; 512][..]);
+    assert!(rpmb.read_blocks(1024, 1).is_err());
+    drop(rpmb);
+
+    /* Nothing to defer with direct I/O */
+    assert!(RpmbBackend::builder(&path).direct_io(true).sync_on_write(false).build().is_err());
+
+    let rpmb = RpmbBackend::builder(&path).direct_io(true).read_only(true).build().unwrap();
+    assert!(rpmb.write_blocks(0, &[0; 256]).is_err());
+}
+
+/*
+ * Total length of our mappings of path.
+ */
+fn mapped_len(path: &std::path::Path) -> u64 {
+    let path = path.to_str().unwrap();
+    std::fs::read_to_string("/proc/self/maps")
+        .unwrap()
+        .lines()
+        .filter(|line| line.ends_with(path))
+        .map(|line| {
+            let range = line.split(' ').next().unwrap();
+            let (start, end) = range.split_at(range.find('-').unwrap());
+            u64::from_str_radix(&end[1..], 16).unwrap() - u64::from_str_radix(start, 16).unwrap()
+        })
+        .sum()
+}
+
+#[test]
+fn test_reserve_tail() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.as_path().join("flash.img");
+    /* One and a half units, the half is somebody else's metadata */
+    std::fs::write(&path, vec![0xaa; 192 * 1024]).unwrap();
+    assert!(RpmbBackend::new(&path).is_err());
+
+    let rpmb = RpmbBackend::builder(&path).reserve_tail(true).build().unwrap();
+    assert_eq!(rpmb.get_capacity(), 1);
+    assert_eq!(mapped_len(&path), 128 * 1024);
+    rpmb.erase().unwrap();
+    assert!(rpmb.read_blocks(512, 1).is_err());
+    drop(rpmb);
+
+    let contents = std::fs::read(&path).unwrap();
+    assert_eq!(&contents[..128 * 1024], &[0; 128 * 1024][..]);
+    assert_eq!(&contents[128 * 1024..], &[0xaa; 64 * 1024][..]);
+
+    /* With a capacity only that much is mapped */
+    let big = dir.as_path().join("big.img");
+    std::fs::write(&big, vec![0; 3 * 128 * 1024 + 64 * 1024]).unwrap();
+    let rpmb = RpmbBackend::builder(&big).reserve_tail(true).capacity(2).build().unwrap();
+    assert_eq!(rpmb.get_capacity(), 2);
+    assert_eq!(mapped_len(&big), SIZE);
+    drop(rpmb);
+    assert!(RpmbBackend::builder(&big).reserve_tail(true).capacity(4).build().is_err());
+}
+
+#[test]
+fn test_block_cache() {
+    let image = TempFile::new().unwrap();
+    image.as_file().set_len(SIZE).unwrap();
+
+    let rpmb = RpmbBackend::builder(image.as_path())
+        .cache_blocks(2)
+        .build()
+        .unwrap();
+
+    /* Writes go through to the image and are cached */
+    rpmb.write_blocks(1, &[0x11; 512]).unwrap();
+    let contents = std::fs::read(image.as_path()).unwrap();
+    assert_eq!(&contents[256..768], &[0x11; 512][..]);
+    assert_eq!(rpmb.read_blocks(1, 2).unwrap(), vec![0x11; 512]);
+    assert_eq!((rpmb.stats().cache_hits, rpmb.stats().cache_misses), (2, 0));
+
+    /* Block 3 pushes out block 1, the least recently used */
+    assert_eq!(rpmb.read_blocks(2, 1).unwrap(), vec![0x11; 256]);
+    assert_eq!(rpmb.read_blocks(3, 1).unwrap(), vec![0; 256]);
+    assert_eq!(rpmb.read_blocks(2, 1).unwrap(), vec![0x11; 256]);
+    assert_eq!((rpmb.stats().cache_hits, rpmb.stats().cache_misses), (4, 1));
+    assert_eq!(rpmb.read_blocks(1, 1).unwrap(), vec![0x11; 256]);
+    assert_eq!((rpmb.stats().cache_hits, rpmb.stats().cache_misses), (4, 2));
+}
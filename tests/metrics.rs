@@ -0,0 +1,33 @@
+/*
+ * Tests of the Prometheus metrics export
+ */
+
+#![cfg(feature = "metrics")]
+
+use vhost_user_rpmb::metrics::render;
+use vhost_user_rpmb::rpmb::RpmbStats;
+
+#[test]
+fn test_render() {
+    let stats = RpmbStats {
+        writes: 7,
+        reads: 3,
+        program_key_requests: 1,
+        write_requests: 9,
+        auth_failures: 2,
+        addr_failures: 0,
+        cache_hits: 12,
+        cache_misses: 4,
+    };
+    let text = render(&stats);
+
+    assert!(text.contains("# TYPE rpmb_current_write_counter gauge\n"));
+    assert!(text.contains("rpmb_current_write_counter 7\n"));
+    assert!(text.contains("rpmb_data_read_total 3\n"));
+    assert!(text.contains("rpmb_program_key_total 1\n"));
+    assert!(text.contains("rpmb_data_write_total 9\n"));
+    assert!(text.contains("rpmb_auth_failures_total 2\n"));
+    assert!(text.contains("rpmb_addr_failures_total 0\n"));
+    assert!(text.contains("rpmb_cache_hits_total 12\n"));
+    assert!(text.contains("rpmb_cache_misses_total 4\n"));
+}
@@ -0,0 +1,95 @@
+/*
+ * Tests of recording requests and replaying them
+ */
+
+mod common;
+
+use common::*;
+use vmm_sys_util::tempdir::TempDir;
+
+use vhost_user_rpmb::record::{replay, Recorder};
+use vhost_user_rpmb::vhu_rpmb::*;
+
+#[test]
+fn test_record_replay() {
+    let dir = TempDir::new().unwrap();
+    let log = dir.as_path().join("requests.jsonl");
+
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_recorder(Recorder::open(&log).unwrap());
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(write_block(&dev, 3, 0, [0x5a; 256]).result, VIRTIO_RPMB_RES_OK);
+    /* A replayed write, the old counter fails */
+    assert_eq!(write_block(&dev, 3, 0, [0xa5; 256]).result, VIRTIO_RPMB_RES_COUNT_FAILURE);
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 3;
+    read.block_count = 1;
+    let (_, resp) = request(&dev, &[read.clone()], 1);
+    assert_eq!(resp[0].data, [0x5a; 256]);
+
+    /* Every chain is one line, and the key isn't in any of them */
+    let recorded = std::fs::read_to_string(&log).unwrap();
+    assert_eq!(recorded.lines().count(), 4);
+    assert!(!recorded.contains(&"42".repeat(32)));
+
+    /* A fresh device given the key does the same thing */
+    let fresh = TestDevice::new();
+    let report = replay(&log, &fresh.rpmb, Some(&KEY[..])).unwrap();
+    assert_eq!(report.chains, 4);
+    assert_eq!(report.mismatches, 0);
+    let (_, resp) = request(&fresh, &[read], 1);
+    assert_eq!(resp[0].data, [0x5a; 256]);
+
+    /* but one that has already seen them can't program the key or write */
+    let report = replay(&log, &fresh.rpmb, Some(&KEY[..])).unwrap();
+    assert_eq!(report.mismatches, 2);
+
+    /* Without the key both signed writes fail to authenticate */
+    let unkeyed = TestDevice::new();
+    let report = replay(&log, &unkeyed.rpmb, None).unwrap();
+    assert_eq!(report.mismatches, 2);
+}
+
+/*
+ * The daemon itself wraps the image in the block cache when asked,
+ * which a replay reports on.
+ */
+#[test]
+fn test_replay_cache_blocks() {
+    use std::process::Command;
+
+    let dir = TempDir::new().unwrap();
+    let log = dir.as_path().join("requests.jsonl");
+
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_recorder(Recorder::open(&log).unwrap());
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(write_block(&dev, 3, 0, [0x5a; 256]).result, VIRTIO_RPMB_RES_OK);
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 3;
+    read.block_count = 1;
+    request(&dev, &[read.clone()], 1);
+    request(&dev, &[read], 1);
+    let key = dir.as_path().join("key");
+    std::fs::write(&key, KEY).unwrap();
+
+    let replay_with = |image: &str, args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_vhost-user-rpmb"))
+            .arg("--flash-path").arg(dir.as_path().join(image))
+            .args(&["--create", "--size", "128K", "--replay"]).arg(&log)
+            .arg("--key-file").arg(&key)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "replay failed: {:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    let cached = replay_with("cached.img", &["--cache-blocks", "4"]);
+    assert!(cached.contains("0 with different results"), "{}", cached);
+    assert!(cached.contains("block cache "), "{}", cached);
+
+    let uncached = replay_with("uncached.img", &[]);
+    assert!(uncached.contains("0 with different results"), "{}", uncached);
+    assert!(!uncached.contains("block cache "), "{}", uncached);
+}
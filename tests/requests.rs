@@ -0,0 +1,729 @@
+/*
+ * End to end tests of the RPMB request handling
+ */
+
+mod common;
+
+use std::time::{Duration, Instant};
+
+use common::*;
+use vm_memory::{Bytes, GuestAddress, GuestAddressSpace};
+use vmm_sys_util::tempdir::TempDir;
+
+use vhost_user_rpmb::rpmb::{KeyStatus, RpmbBackend, NONCE_WINDOW};
+use vhost_user_rpmb::vhu_rpmb::*;
+
+#[test]
+fn test_program_key() {
+    let dev = TestDevice::new();
+
+    let resp = program_key(&dev);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_PROGRAM_KEY);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_OK);
+
+    /* The key can only be programmed once */
+    let resp = program_key(&dev);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_WRITE_FAILURE);
+}
+
+#[test]
+fn test_get_write_counter() {
+    let dev = TestDevice::new();
+    let mut counter = Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER);
+    counter.nonce = [0xa5; 16];
+
+    let (used, resp) = request(&dev, &[counter.clone()], 1);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_GET_COUNTER);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_NO_AUTH_KEY);
+
+    program_key(&dev);
+    let (_, resp) = request(&dev, &[counter], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp[0].write_counter, 0);
+    assert_eq!(resp[0].nonce, [0xa5; 16]);
+    assert_eq!(resp[0].key_mac, mac(&KEY, &resp));
+}
+
+#[test]
+fn test_unkeyed_counter_read() {
+    /* The counter is reported but can't be signed without a key */
+    let dev = TestDevice::new();
+    dev.rpmb.backend().set_write_count(3);
+    let (_, resp) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_NO_AUTH_KEY);
+    assert_eq!(resp[0].write_counter, 3);
+    assert_eq!(resp[0].key_mac, [0; 32]);
+}
+
+#[test]
+fn test_unkeyed_write() {
+    let dev = TestDevice::with_image();
+    let resp = write_block(&dev, 2, 0, [0x5a; 256]);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_NO_AUTH_KEY);
+
+    /* Neither the image nor the counter changed */
+    let contents = std::fs::read(dev.image.as_ref().unwrap().as_path()).unwrap();
+    assert_eq!(contents, vec![0; 128 * 1024]);
+    assert_eq!(dev.rpmb.backend().get_write_count(), 0);
+}
+
+#[test]
+fn test_keyed_counter_read() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    dev.rpmb.backend().set_write_count(3);
+    let (_, resp) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp[0].write_counter, 3);
+    assert_eq!(resp[0].key_mac, mac(&KEY, &resp));
+    assert_ne!(resp[0].key_mac, mac(&[0x24; 32], &resp));
+}
+
+#[test]
+fn test_initial_counter() {
+    let dir = TempDir::new().unwrap();
+    let backend = RpmbBackend::builder(&dir.as_path().join("flash.img"))
+        .create_size(128 * 1024)
+        .initial_counter(41)
+        .build()
+        .unwrap();
+    let dev = TestDevice { image: None, rpmb: VhostUserRpmb::new(backend).unwrap() };
+
+    /* Still there once the driver has reset the device */
+    dev.rpmb.reset().unwrap();
+    program_key(&dev);
+    assert_eq!(write_block(&dev, 0, 41, [0x11; 256]).write_counter, 42);
+}
+
+#[test]
+fn test_counter_expired() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    dev.rpmb.backend().set_write_count(u32::MAX);
+
+    let resp = write_block(&dev, 0, u32::MAX, [0x11; 256]);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_WRITE_COUNTER_EXPIRED);
+    assert_eq!(dev.rpmb.backend().get_write_count(), u32::MAX);
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.block_count = 1;
+    let (_, resp) = request(&dev, &[read], 1);
+    assert_eq!(resp[0].data, [0; 256]);
+}
+
+#[test]
+fn test_write_then_read() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    let resp = write_block(&dev, 3, 0, [0x5a; 256]);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_OK);
+
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 3;
+    read.block_count = 1;
+    read.nonce = [0x11; 16];
+    let (used, resp) = request(&dev, &[read], 1);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_DATA_READ);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp[0].address, 3);
+    assert_eq!(resp[0].nonce, [0x11; 16]);
+    assert_eq!(resp[0].data[..], [0x5a; 256][..]);
+    assert_eq!(resp[0].key_mac, mac(&KEY, &resp));
+
+    /* and the counter moved on */
+    let (_, resp) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(resp[0].write_counter, 1);
+}
+
+#[test]
+fn test_multi_block_read() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    for block in 0..3u8 {
+        write_block(&dev, 4 + block as u16, block as u32, [block + 1; 256]);
+    }
+
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 4;
+    read.block_count = 3;
+    read.nonce = [0x33; 16];
+    let (used, resp) = request(&dev, &[read.clone()], 3);
+    assert_eq!(used as usize, 3 * FRAME_SIZE);
+    for (block, frame) in resp.iter().enumerate() {
+        assert_eq!(frame.req_resp, VIRTIO_RPMB_RESP_DATA_READ);
+        assert_eq!(frame.result, VIRTIO_RPMB_RES_OK);
+        assert_eq!(frame.address, 4);
+        assert_eq!(frame.block_count, 3);
+        assert_eq!(frame.nonce, [0x33; 16]);
+        assert_eq!(frame.data[..], [block as u8 + 1; 256][..]);
+    }
+    /* One MAC over all three, in the last frame */
+    assert_eq!(resp[2].key_mac, mac(&KEY, &resp));
+    assert_eq!(resp[0].key_mac, [0; 32]);
+
+    /* Too few buffers for the blocks asked for */
+    let (used, resp) = request(&dev, &[read], 2);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+}
+
+#[test]
+fn test_indirect_read() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    write_block(&dev, 7, 0, [0xc3; 256]);
+
+    /* The same read but described through an indirect table */
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 7;
+    read.block_count = 1;
+    let mut queue = TestQueue::new();
+    let addrs = queue.add_indirect_chain(&[read], 1);
+    let used = queue.process(&dev.rpmb).pop().unwrap().unwrap();
+    assert_eq!(used as usize, FRAME_SIZE);
+
+    let resp = queue.read_frame(addrs[0]);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_DATA_READ);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp.data[..], [0xc3; 256][..]);
+}
+
+fn two_block_write(dev: &TestDevice) -> u16 {
+    let mut frames = [Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE), Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE)];
+    for (i, frame) in frames.iter_mut().enumerate() {
+        frame.address = 4;
+        frame.block_count = 2;
+        frame.data = [i as u8 + 1; 256];
+    }
+    sign(&KEY, &mut frames);
+    let mut chain = frames.to_vec();
+    chain.push(Frame::new(VIRTIO_RPMB_REQ_RESULT_READ));
+    let (_, resp) = request(dev, &chain, 1);
+    resp[0].result
+}
+
+#[test]
+fn test_two_writes_in_one_chain() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    let signed_write = |counter| {
+        let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+        write.block_count = 1;
+        write.write_counter = counter;
+        write.data = [counter as u8 + 1; 256];
+        let mut frames = [write];
+        sign(&KEY, &mut frames);
+        frames[0].clone()
+    };
+    let chain = [signed_write(0), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ),
+                 signed_write(1), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)];
+    let (_, resp) = request(&dev, &chain, 2);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+    assert_eq!(resp[1].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* Neither write happened */
+    assert_eq!(dev.rpmb.stats().writes, 0);
+}
+
+#[test]
+fn test_key_and_write_in_one_chain() {
+    let dev = TestDevice::new();
+
+    let mut program = Frame::new(VIRTIO_RPMB_REQ_PROGRAM_KEY);
+    program.key_mac = KEY;
+    program.block_count = 1;
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.block_count = 1;
+    let mut frames = [write];
+    sign(&KEY, &mut frames);
+    let chain = [program, frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)];
+    let (_, resp) = request(&dev, &chain, 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* The key wasn't programmed behind the guest's back */
+    assert!(!dev.rpmb.backend().has_key());
+    assert_eq!(dev.rpmb.stats().writes, 0);
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_disagreeing_write_frames() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_max_write_blocks(2).unwrap();
+    program_key(&dev);
+
+    let mut frames = [Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE), Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE)];
+    for frame in frames.iter_mut() {
+        frame.block_count = 2;
+    }
+    frames[1].address = 8;
+    sign(&KEY, &mut frames);
+    let mut chain = frames.to_vec();
+    chain.push(Frame::new(VIRTIO_RPMB_REQ_RESULT_READ));
+    let (_, resp) = request(&dev, &chain, 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+    assert_eq!(dev.rpmb.stats().writes, 0);
+}
+
+#[test]
+fn test_max_write_blocks() {
+    let mut dev = TestDevice::new();
+    program_key(&dev);
+
+    /* By default only single block writes are allowed */
+    assert_eq!(two_block_write(&dev), VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    dev.rpmb.set_max_write_blocks(2).unwrap();
+    assert_eq!(dev.rpmb.config().max_wr_cnt, 2);
+    assert_eq!(two_block_write(&dev), VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_four_block_write() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_max_write_blocks(4).unwrap();
+    program_key(&dev);
+
+    let mut frames: Vec<Frame> = (0..4u8)
+        .map(|i| {
+            let mut frame = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+            frame.address = 8;
+            frame.block_count = 4;
+            frame.data = [i + 1; 256];
+            frame
+        })
+        .collect();
+    sign(&KEY, &mut frames);
+    frames.push(Frame::new(VIRTIO_RPMB_REQ_RESULT_READ));
+    let (_, resp) = request(&dev, &frames, 1);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp[0].block_count, 4);
+    /* The whole write only advances the counter once */
+    assert_eq!(resp[0].write_counter, 1);
+    assert_eq!(dev.rpmb.stats().writes, 1);
+
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.address = 8;
+    read.block_count = 4;
+    let (_, resp) = request(&dev, &[read], 4);
+    for (block, frame) in resp.iter().enumerate() {
+        assert_eq!(frame.result, VIRTIO_RPMB_RES_OK);
+        assert_eq!(frame.data[..], [block as u8 + 1; 256][..]);
+    }
+}
+
+#[test]
+fn test_multiple_program_key_frames() {
+    let dev = TestDevice::new();
+
+    let mut first = Frame::new(VIRTIO_RPMB_REQ_PROGRAM_KEY);
+    first.key_mac = KEY;
+    first.block_count = 1;
+    let mut second = first.clone();
+    second.key_mac = [0x99; 32];
+
+    let (used, resp) = request(&dev, &[first, second, Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 2);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_PROGRAM_KEY);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+
+    /* The first key is the one that stuck */
+    assert_eq!(write_block(&dev, 0, 0, [0x01; 256]).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_nonzero_result_rejected() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    let mut counter = Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER);
+    counter.result = VIRTIO_RPMB_RES_OK + 1;
+    let (_, resp) = request(&dev, &[counter], 1);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_GET_COUNTER);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* Even a correctly signed write is refused */
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.block_count = 1;
+    write.result = 0x8000;
+    let mut frames = [write];
+    sign(&KEY, &mut frames);
+    let (_, resp) = request(&dev, &[frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+}
+
+#[test]
+fn test_used_length_of_deferred_result() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    /* A write on its own leaves its response buffer untouched */
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.block_count = 1;
+    let mut frames = [write];
+    sign(&KEY, &mut frames);
+    let (used, _) = request(&dev, &frames, 1);
+    assert_eq!(used, 0);
+
+    /* and the result is accounted for when it is read */
+    let (used, resp) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_zero_block_count() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    let read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    let (used, resp) = request(&dev, &[read], 1);
+    assert_eq!(used as usize, FRAME_SIZE);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_DATA_READ);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    let mut frames = [Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE)];
+    sign(&KEY, &mut frames);
+    let (_, resp) = request(&dev, &[frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+}
+
+#[test]
+fn test_queue_survives_bad_chains() {
+    let dev = TestDevice::new();
+    let mut queue = TestQueue::new();
+
+    /* Outside guest memory, then a request with a short response buffer */
+    queue.add_raw_chain(&[(GuestAddress(0x20_0000), FRAME_SIZE as u32, false),
+                          (GuestAddress(0x8_1000), FRAME_SIZE as u32, true)]);
+    queue.add_raw_chain(&[(GuestAddress(0x8_0000), FRAME_SIZE as u32, false),
+                          (GuestAddress(0x8_1000), 64, true)]);
+    assert_eq!(queue.process_chains(&dev.rpmb), vec![(0, 0), (2, 0)]);
+
+    /* Later requests on the same queue are still served */
+    let addrs = queue.add_chain(&[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(queue.process_chains(&dev.rpmb)[2], (4, FRAME_SIZE as u32));
+    assert_eq!(queue.read_frame(addrs[0]).req_resp, VIRTIO_RPMB_RESP_GET_COUNTER);
+}
+
+#[test]
+fn test_read_without_response_buffer() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    let mut queue = TestQueue::new();
+
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.block_count = 1;
+    queue.add_chain(&[read.clone()], 0);
+    let results = queue.process(&dev.rpmb);
+    assert!(matches!(results[0], Err(Error::UnexpectedReadDescriptor)));
+
+    /* Through the queue handler it is dropped and the queue carries on */
+    let mut queue = TestQueue::new();
+    queue.add_chain(&[read], 0);
+    assert_eq!(queue.process_chains(&dev.rpmb), vec![(0, 0)]);
+    let addrs = queue.add_chain(&[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(queue.process_chains(&dev.rpmb)[1], (1, FRAME_SIZE as u32));
+    assert_eq!(queue.read_frame(addrs[0]).req_resp, VIRTIO_RPMB_RESP_GET_COUNTER);
+}
+
+#[test]
+fn test_malformed_chain_skipped() {
+    let dev = TestDevice::new();
+    let mut queue = TestQueue::new();
+
+    /* A lone response buffer with no request in front of it */
+    let lone = queue.add_chain(&[], 1)[0];
+    let addrs = queue.add_chain(&[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+
+    /* The bad chain is returned empty and doesn't stop the good one */
+    assert_eq!(queue.process_chains(&dev.rpmb), vec![(0, 0), (1, FRAME_SIZE as u32)]);
+    assert!(!queue.written(lone));
+    let resp = queue.read_frame(addrs[0]);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_GET_COUNTER);
+}
+
+#[test]
+fn test_short_response_buffer() {
+    let dev = TestDevice::new();
+    let mut queue = TestQueue::new();
+
+    let request = GuestAddress(0x8_0000);
+    let response = GuestAddress(0x8_1000);
+    let mem = queue.mem.memory();
+    mem.write_slice(&Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER).to_bytes(), request).unwrap();
+    mem.write_slice(&[0xff; 128], response).unwrap();
+    queue.add_raw_chain(&[(request, FRAME_SIZE as u32, false), (response, 128, true)]);
+
+    let results = queue.process(&dev.rpmb);
+    assert!(matches!(results[0], Err(Error::UnexpectedDescriptorSize)));
+
+    /* and nothing was written into the short buffer */
+    let mut buf = [0; 128];
+    mem.read_slice(&mut buf, response).unwrap();
+    assert_eq!(buf, [0xff; 128]);
+}
+
+#[test]
+fn test_unmapped_descriptor() {
+    let dev = TestDevice::new();
+    let mut queue = TestQueue::new();
+
+    /* A request frame past the end of guest memory */
+    let request = GuestAddress(0x20_0000);
+    let response = GuestAddress(0x8_1000);
+    queue.add_raw_chain(&[(request, FRAME_SIZE as u32, false), (response, FRAME_SIZE as u32, true)]);
+
+    /* and a response frame that runs off the end */
+    let request = GuestAddress(0x8_0000);
+    let response = GuestAddress(0x10_0000 - 256);
+    queue.mem.memory()
+        .write_slice(&Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER).to_bytes(), request)
+        .unwrap();
+    queue.add_raw_chain(&[(request, FRAME_SIZE as u32, false), (response, FRAME_SIZE as u32, true)]);
+
+    let results = queue.process(&dev.rpmb);
+    assert!(matches!(results[0], Err(Error::DescriptorNotMapped(0x20_0000))));
+    assert!(matches!(results[1], Err(Error::DescriptorNotMapped(0xf_ff00))));
+}
+
+#[test]
+fn test_key_status() {
+    let backend = RpmbBackend::in_memory(1);
+    assert_eq!(backend.key_status(), KeyStatus::Empty);
+
+    backend.program_key(KEY.iter().copied().collect()).unwrap();
+    assert_eq!(backend.key_status(), KeyStatus::Programmed);
+}
+
+fn program_zero_key(dev: &TestDevice) -> u16 {
+    let mut program = Frame::new(VIRTIO_RPMB_REQ_PROGRAM_KEY);
+    program.block_count = 1;
+    let (_, resp) = request(dev, &[program, Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    resp[0].result
+}
+
+#[test]
+fn test_reject_zero_key() {
+    /* Accepted unless asked otherwise */
+    let dev = TestDevice::new();
+    assert_eq!(program_zero_key(&dev), VIRTIO_RPMB_RES_OK);
+
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_reject_zero_key(true);
+    assert_eq!(program_zero_key(&dev), VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* The device is still unkeyed so a real key can be programmed */
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_malformed_program_key() {
+    let dev = TestDevice::new();
+    let malformed: [fn(&mut Frame); 4] = [
+        |f| f.block_count = 2,
+        |f| f.address = 1,
+        |f| f.write_counter = 1,
+        |f| f.nonce = [0x11; 16],
+    ];
+    for malform in malformed.iter() {
+        let mut program = Frame::new(VIRTIO_RPMB_REQ_PROGRAM_KEY);
+        program.key_mac = KEY;
+        program.block_count = 1;
+        malform(&mut program);
+        let (_, resp) = request(&dev, &[program, Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+        assert_eq!(resp[0].req_resp, VIRTIO_RPMB_RESP_PROGRAM_KEY);
+        assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+        assert!(!dev.rpmb.backend().has_key());
+    }
+
+    /* A well formed one still works afterwards */
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_programmed_key_used() {
+    let dev = TestDevice::new();
+    assert_eq!(program_key(&dev).result, VIRTIO_RPMB_RES_OK);
+
+    /* Requests signed with the key we sent authenticate */
+    assert_eq!(write_block(&dev, 0, 0, [0x5a; 256]).result, VIRTIO_RPMB_RES_OK);
+
+    /* ...and the key is the one we sent, not just any key */
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.block_count = 1;
+    write.write_counter = 1;
+    let mut frames = [write];
+    sign(&[0x24; 32], &mut frames);
+    let (_, resp) = request(&dev, &[frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_AUTH_FAILURE);
+}
+
+#[test]
+fn test_reset_drops_pending_result() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+
+    /* Leave a write result pending */
+    let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+    write.block_count = 1;
+    let mut frames = [write];
+    sign(&KEY, &mut frames);
+    request(&dev, &frames, 1);
+
+    /* Resetting the device drops the result nobody read */
+    dev.rpmb.reset().unwrap();
+    let (used, _) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+    assert_eq!(used, 0);
+
+    /* and the device keeps working with the same counter */
+    assert_eq!(write_block(&dev, 0, 1, [0; 256]).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_reset_keeps_counter() {
+    let dev = TestDevice::new();
+    program_key(&dev);
+    assert_eq!(write_block(&dev, 0, 0, [0x11; 256]).result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(write_block(&dev, 1, 1, [0x22; 256]).result, VIRTIO_RPMB_RES_OK);
+
+    /* As happens every time the driver comes up */
+    dev.rpmb.reset().unwrap();
+    let (_, resp) = request(&dev, &[Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER)], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp[0].write_counter, 2);
+
+    /* So the old writes can't be replayed */
+    assert_eq!(write_block(&dev, 0, 0, [0x11; 256]).result, VIRTIO_RPMB_RES_COUNT_FAILURE);
+}
+
+#[test]
+fn test_program_key_result_counter() {
+    let dir = TempDir::new().unwrap();
+    let counter = dir.as_path().join("counter");
+    std::fs::write(&counter, 5u32.to_le_bytes()).unwrap();
+    let backend = RpmbBackend::builder(&dir.as_path().join("flash.img"))
+        .create_size(128 * 1024)
+        .counter_file(&counter)
+        .build()
+        .unwrap();
+    let dev = TestDevice { image: None, rpmb: VhostUserRpmb::new(backend).unwrap() };
+
+    let resp = program_key(&dev);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp.write_counter, 5);
+    assert_eq!(resp.address, 0);
+
+    /* A write result reports the new counter and where it went */
+    let resp = write_block(&dev, 3, 5, [0x11; 256]);
+    assert_eq!(resp.req_resp, VIRTIO_RPMB_RESP_DATA_WRITE);
+    assert_eq!(resp.result, VIRTIO_RPMB_RES_OK);
+    assert_eq!(resp.write_counter, 6);
+    assert_eq!(resp.address, 3);
+    assert_eq!(resp.block_count, 1);
+}
+
+#[test]
+fn test_auth_failure_lockout() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_max_auth_failures(3);
+    program_key(&dev);
+
+    let bad_write = |dev: &TestDevice| {
+        let mut write = Frame::new(VIRTIO_RPMB_REQ_DATA_WRITE);
+        write.block_count = 1;
+        let mut frames = [write];
+        sign(&[0x24; 32], &mut frames);
+        let (_, resp) = request(dev, &[frames[0].clone(), Frame::new(VIRTIO_RPMB_REQ_RESULT_READ)], 1);
+        resp[0].result
+    };
+
+    for _ in 0..3 {
+        assert_eq!(bad_write(&dev), VIRTIO_RPMB_RES_AUTH_FAILURE);
+    }
+
+    /* Even a correctly signed write is now refused */
+    assert_eq!(write_block(&dev, 0, 0, [0; 256]).result, VIRTIO_RPMB_RES_AUTH_FAILURE);
+    assert_eq!(dev.rpmb.stats().writes, 0);
+
+    dev.rpmb.reset().unwrap();
+    assert_eq!(write_block(&dev, 0, 0, [0; 256]).result, VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_profiling() {
+    let mut dev = TestDevice::new();
+    assert!(dev.rpmb.latency().is_none());
+
+    dev.rpmb.enable_profiling();
+    program_key(&dev);
+    write_block(&dev, 0, 0, [0; 256]);
+    write_block(&dev, 1, 1, [0; 256]);
+
+    let latency = dev.rpmb.latency().unwrap();
+    let count = |name| latency.iter().find(|(n, _)| *n == name).map(|(_, s)| s.count);
+    assert_eq!(count("program_key"), Some(1));
+    assert_eq!(count("data_write"), Some(2));
+    assert_eq!(count("result_read"), Some(3));
+    assert_eq!(count("data_read"), None);
+}
+
+#[test]
+fn test_strict_nonce() {
+    let mut dev = TestDevice::new();
+    program_key(&dev);
+
+    let read = |dev: &TestDevice, nonce: u8| {
+        let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+        read.block_count = 1;
+        read.nonce = [nonce; 16];
+        let (_, resp) = request(dev, &[read], 1);
+        resp[0].result
+    };
+
+    /* Reuse is fine by default */
+    assert_eq!(read(&dev, 1), VIRTIO_RPMB_RES_OK);
+    assert_eq!(read(&dev, 1), VIRTIO_RPMB_RES_OK);
+
+    dev.rpmb.set_strict_nonce(true);
+    assert_eq!(read(&dev, 2), VIRTIO_RPMB_RES_OK);
+    assert_eq!(read(&dev, 2), VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* Counter reads share the window */
+    let mut counter = Frame::new(VIRTIO_RPMB_REQ_GET_WRITE_COUNTER);
+    counter.nonce = [2; 16];
+    let (_, resp) = request(&dev, &[counter], 1);
+    assert_eq!(resp[0].result, VIRTIO_RPMB_RES_GENERAL_FAILURE);
+
+    /* Once it has dropped out of the window it can be used again */
+    for nonce in 3..3 + NONCE_WINDOW as u8 {
+        assert_eq!(read(&dev, nonce), VIRTIO_RPMB_RES_OK);
+    }
+    assert_eq!(read(&dev, 2), VIRTIO_RPMB_RES_OK);
+}
+
+#[test]
+fn test_response_delay() {
+    let mut dev = TestDevice::new();
+    dev.rpmb.set_response_delay(Duration::from_millis(20));
+    program_key(&dev);
+    for block in 0..3u16 {
+        write_block(&dev, block, block as u32, [0x42; 256]);
+    }
+
+    /* Every frame of the response is held back */
+    let mut read = Frame::new(VIRTIO_RPMB_REQ_DATA_READ);
+    read.block_count = 3;
+    let start = Instant::now();
+    let (_, resp) = request(&dev, &[read], 3);
+    assert!(start.elapsed() >= Duration::from_millis(60));
+    assert_eq!(resp[2].result, VIRTIO_RPMB_RES_OK);
+}